@@ -115,7 +115,16 @@ async def wand_set_button_increments(low: int, high: int):
 @click.argument("mid_high", type=click.INT)
 @click.argument("top", type=click.INT)
 async def wand_set_light_mappings(bottom: int, mid_low: int, mid_high: int, top: int):
-    print(await client.set_light_mappings(bottom, mid_low, mid_high, top))
+    print(await client.wand_set_light_mappings(bottom, mid_low, mid_high, top))
+
+@cli.command()
+async def wand_get_light_mappings():
+    print(await client.wand_get_light_mappings())
+
+@cli.command()
+@click.argument("mode", type=click.Choice(["intensity", "off", "on", "pattern"]))
+async def wand_set_light_mode(mode: str):
+    print(await client.wand_set_light_mode(mode))
 
 
 @cli.command()
@@ -126,7 +135,12 @@ async def wand_set_percent(percent: int):
 
 @cli.command()
 async def wand_get_percent():
-    print(await client.wand_get_percent())
+    res = await client.wand_get_percent()
+    if res.error is not None:
+        print(res.error)
+        return
+
+    print(f"target: {res.result['target']}%, actual: {res.result['actual']}%")
 
 
 @cli.command()
@@ -139,6 +153,37 @@ async def sys_health():
     print(await client.sys_health())
 
 
+@cli.command()
+async def sys_describe():
+    res = await client.sys_describe()
+    if res.error is not None:
+        print(res.error)
+        return
+
+    print(json.dumps(res.result, indent=2))
+
+
+@cli.command()
+async def sys_diagnostics():
+    print(await client.sys_diagnostics())
+
+
+@cli.command()
+async def pd_status():
+    print(await client.pd_status())
+
+
+@cli.command()
+async def pd_capabilities():
+    print(await client.pd_capabilities())
+
+
+@cli.command()
+@click.argument("voltage", type=click.Choice(["5V", "9V", "12V"]))
+async def pd_select(voltage: str):
+    print(await client.pd_select(voltage))
+
+
 @cli.command()
 @click.argument("msg")
 async def uart_send(msg: str):
@@ -149,6 +194,31 @@ async def uart_send(msg: str):
 async def uart_get_last():
     print(await client.uart_get_last())
 
+
+@cli.command()
+@click.argument("data")
+@click.option("--timeout", type=click.INT, default=None, help="wait this many ms for a reply")
+async def uart_send_raw(data: str, timeout: int | None):
+    print(await client.uart_send_raw(data.encode().decode("unicode_escape"), timeout))
+
+
+@cli.command()
+@click.argument("baud_rate", type=click.INT)
+@click.option("--parity", type=click.Choice(["none", "even", "odd"]), default="none")
+@click.option("--stop-bits", type=click.Choice(["one", "one_and_half", "two"]), default="one")
+async def uart_configure(baud_rate: int, parity: str, stop_bits: str):
+    print(await client.uart_configure(baud_rate, parity, stop_bits))
+
+
+@cli.command()
+async def uart_panel_stats():
+    print(await client.uart_panel_stats())
+
+
+@cli.command()
+async def uart_unknown_messages():
+    print(await client.uart_unknown_messages())
+
 @cli.command()
 async def build_update_firmware():
     subprocess.run(["cargo", "build", "--release"], check=True)
@@ -20,6 +20,10 @@ def wpa2_enterprise_conf(ssid: str, identity: str, username: str, password: str)
         }
     }
 
+class WandLevel(typing.TypedDict):
+    target: int
+    actual: int
+
 class Client():
     def __init__(self):
         self.http = HTTPRpc()
@@ -57,8 +61,21 @@ class Client():
     
     async def uart_send(self, msg: str):
         return await self.make_call("uart", "send", [msg])
+
+    async def uart_send_raw(self, data: str, timeout_ms: int | None = None):
+        args = [data] if timeout_ms is None else [data, timeout_ms]
+        return await self.make_call("uart", "send_raw", args)
+
+    async def uart_configure(self, baud_rate: int, parity: str, stop_bits: str):
+        return await self.make_call("uart", "configure", [{"baud_rate": baud_rate, "parity": parity, "stop_bits": stop_bits}])
+
+    async def uart_panel_stats(self):
+        return await self.make_call("uart", "panel_stats", [])
+
+    async def uart_unknown_messages(self):
+        return await self.make_call("uart", "unknown_messages", [])
     
-    async def wand_get_percent(self) -> RPCResponse[int]:
+    async def wand_get_percent(self) -> RPCResponse[WandLevel]:
         return await self.make_call("wand", "get_percent", [])
     
     async def wand_set_percent(self, pct: int):
@@ -69,6 +86,12 @@ class Client():
    
     async def wand_set_light_mappings(self, bottom: int, mid_low: int, mid_high: int, top: int):
         return await self.make_call("wand", "set_light_mappings", [bottom, mid_low, mid_high, top])
+
+    async def wand_get_light_mappings(self):
+        return await self.make_call("wand", "get_light_mappings", [])
+
+    async def wand_set_light_mode(self, mode: str):
+        return await self.make_call("wand", "set_light_mode", [mode])
    
     async def wand_set_button_increments(self, bottom: int, top: int):
         return await self.make_call("wand", "set_button_increments", [bottom, top])
@@ -80,4 +103,19 @@ class Client():
         return await self.make_call("sys", "build_info", [])
     
     async def sys_health(self):
-        return await self.make_call("sys", "health", [])
\ No newline at end of file
+        return await self.make_call("sys", "health", [])
+
+    async def sys_describe(self):
+        return await self.make_call("sys", "describe", [])
+
+    async def sys_diagnostics(self):
+        return await self.make_call("sys", "diagnostics", [])
+
+    async def pd_status(self):
+        return await self.make_call("pd", "status", [])
+
+    async def pd_capabilities(self):
+        return await self.make_call("pd", "capabilities", [])
+
+    async def pd_select(self, voltage):
+        return await self.make_call("pd", "select_pdo", [voltage])
\ No newline at end of file
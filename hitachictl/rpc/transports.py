@@ -6,7 +6,7 @@ import functools
 import json
 import os
 import typing
-from typing import Any, Optional
+from typing import Any, Callable, Optional
 import logging
 import aiofiles
 import aiohttp
@@ -123,6 +123,21 @@ class HTTPRpc(RPCClient):
             res.raise_for_status()
             return await res.text()
     
+# fragment header: 0xF0 | MORE, sequence number, total length (u16 LE)
+FRAME_MARKER = 0xF0
+FRAME_MORE = 0x01
+FRAME_HEADER_LEN = 4
+
+
+def split_frames(message: bytes, fragment_len: int):
+    chunk_len = fragment_len - FRAME_HEADER_LEN
+    chunks = [message[i:i + chunk_len] for i in range(0, len(message), chunk_len)] or [b""]
+    total = len(message).to_bytes(2, "little")
+    for seq, chunk in enumerate(chunks):
+        flags = FRAME_MORE if seq + 1 < len(chunks) else 0
+        yield bytes([FRAME_MARKER | flags, seq & 0xFF]) + total + chunk
+
+
 class BLERpc(RPCClient):
     REQ_CHAR = "813f9733-95c9-49ba-84a0-d0167c260eef"
     RES_CHAR = "23ad909d-511b-4fad-ad85-0bf102eee315"
@@ -131,6 +146,10 @@ class BLERpc(RPCClient):
         self.pending_requests: dict[int, Future[RPCResponse[Any]]] = {}
         self.conn: Optional[BleakClient] = None
         self.id_counter = 0
+        self.incoming = bytearray()
+        self.receiving = False
+        # called with the params of every sys:event notification the wand pushes
+        self.on_event: Callable[[dict[str, Any]], None] = lambda event: logging.info(f"Event: {event}")
 
     async def discover(self, rediscover=False):
         if not rediscover and self.conn is not None:
@@ -152,7 +171,25 @@ class BLERpc(RPCClient):
         return True
 
     def response_notify(self, sender: Any, data: bytearray):
-        res = RPCResponse(**json.loads(data.decode("utf8")))
+        if data[0] & ~FRAME_MORE & 0xFF != FRAME_MARKER:
+            logging.warning(f"Unframed response: {data}")
+            return
+
+        # sequence numbers wrap on long messages, so only a finished message starts a new one
+        if not self.receiving:
+            self.incoming.clear()
+        self.incoming.extend(data[FRAME_HEADER_LEN:])
+        self.receiving = bool(data[0] & FRAME_MORE)
+        if self.receiving:
+            return
+
+        message = json.loads(self.incoming.decode("utf8"))
+        # notifications carry a method and no id, they aren't answering anything
+        if "method" in message and "id" not in message:
+            self.on_event(message.get("params"))
+            return
+
+        res = RPCResponse(**message)
         req = self.pending_requests.get(res.res_id)
         if not req:
             logging.warning(f"Orphaned response: {res}")
@@ -178,13 +215,12 @@ class BLERpc(RPCClient):
                 {"method": f"{namespace}:{method}", "id": req_id, "params": list(*args)}
             ))
 
-        await self.conn.write_gatt_char(
-            BLERpc.REQ_CHAR,
-            json.dumps(
-                {"method": f"{namespace}:{method}", "id": req_id, "params": list(*args)}
-            ).encode("utf8"),
-            response=False,
-        )
+        message = json.dumps(
+            {"method": f"{namespace}:{method}", "id": req_id, "params": list(*args)}
+        ).encode("utf8")
+        # 3 bytes of every write go to the ATT header
+        for fragment in split_frames(message, self.conn.mtu_size - 3):
+            await self.conn.write_gatt_char(BLERpc.REQ_CHAR, fragment, response=False)
 
         try:
             res = await self.pending_requests[req_id]
@@ -0,0 +1,15 @@
+// Hardware independent parts of the firmware, built for the host so their tests can run.
+// Run with `cargo test` from this directory.
+
+#[path = "../../src/diagnostics.rs"]
+pub mod diagnostics;
+#[path = "../../src/gesture.rs"]
+pub mod gesture;
+#[path = "../../src/hal/panel.rs"]
+pub mod panel;
+#[path = "../../src/registry.rs"]
+pub mod registry;
+#[path = "../../src/rpc.rs"]
+pub mod rpc;
+#[path = "../../src/schema.rs"]
+pub mod schema;
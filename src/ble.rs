@@ -1,27 +1,67 @@
+use std::{collections::HashMap, sync::Arc};
+
 use esp32_nimble::{
     enums::{AuthReq, SecurityIOCap},
     utilities::BleUuid,
-    uuid128, BLEAdvertisementData, BLEDevice, NimbleProperties,
+    uuid128, BLEAdvertisementData, BLECharacteristic, BLEDevice, BLEServer, NimbleProperties,
+    OnWriteArgs,
 };
+use thingbuf::mpsc::blocking::StaticSender;
 
-use crate::rpc::{MessageSource, ResponseTag, RpcRequester};
+use crate::{
+    diagnostics::Fault,
+    events::Event,
+    framing::{self, Reassembler},
+    hal::lights::{Animation, LightCommand},
+    rpc::{
+        Encoding, MessageRecycler, MessageSource, RequestMessage, ResponseTag, RpcError,
+        RpcRequester, RpcResponder, RpcResponse, RpcVersion, PARSE_ERROR,
+    },
+};
 
+// requests and responses on both RPC characteristics are split up as described in `framing`
 const RPC_REQ_CHAR: BleUuid = uuid128!("813f9733-95c9-49ba-84a0-d0167c260eef");
 const RPC_RES_CHAR: BleUuid = uuid128!("23ad909d-511b-4fad-ad85-0bf102eee315");
+// same calls as above, but MessagePack in and out
+const RPC_MSGPACK_REQ_CHAR: BleUuid = uuid128!("9792fe74-04e0-4c8b-a219-55d0eea22210");
+const RPC_MSGPACK_RES_CHAR: BleUuid = uuid128!("285ed0cf-d4de-404b-88ac-e4a215c67319");
 const LOG_CHAR: BleUuid = uuid128!("b170b38a-eff7-4883-b946-50e07c390200");
 
 const LOVENSE_RX_CHAR: BleUuid = uuid128!("54300002-0023-4bd4-bbd5-a6920e4c5653");
 const LOVENSE_TX_CHAR: BleUuid = uuid128!("54300003-0023-4bd4-bbd5-a6920e4c5653");
 
+// what every client gets before negotiating a bigger MTU
+const DEFAULT_ATT_MTU: u16 = 23;
+
 const LOVENSE_SERVICE_ID: BleUuid = uuid128!("54300001-0023-4bd4-bbd5-a6920e4c5653");
 // const ESPWAND_SERVICE_ID: BleUuid = uuid128!("af12176f-36e8-4d06-8a03-a1563f0a7baf");
 
+// half finished messages, per connection handle
+type Reassemblers = Arc<parking_lot::Mutex<HashMap<u16, Reassembler>>>;
+
 // pub fn run_ble(req_tx: StaticSender<Vec<u8>>, res_rx: StaticReceiver<Vec<u8>>) {
-pub fn run_ble(engine: RpcRequester) {
+pub fn run_ble(
+    engine: RpcRequester,
+    res_tx: RpcResponder,
+    lights: StaticSender<LightCommand>,
+    events: StaticSender<Event>,
+) {
     let device = BLEDevice::take();
 
     let RpcRequester { req_tx, res_rx } = engine;
     let lovense_req_tx = req_tx.clone();
+    let disconnect_events = events.clone();
+
+    let json_reassemblers = Reassemblers::default();
+    let msgpack_reassemblers = Reassemblers::default();
+    let disconnect_reassemblers = [
+        Arc::clone(&json_reassemblers),
+        Arc::clone(&msgpack_reassemblers),
+    ];
+    let response_reassemblers = [
+        Arc::clone(&json_reassemblers),
+        Arc::clone(&msgpack_reassemblers),
+    ];
 
     device
         .security()
@@ -34,16 +74,35 @@ pub fn run_ble(engine: RpcRequester) {
 
     let server = device.get_server();
 
-    server.on_connect(|server, desc| {
+    server.on_connect(move |server, desc| {
         log::info!("hewwo to {desc:?}");
+        let _ = lights.send(LightCommand::Play(Animation::Blink));
+        let _ = events.try_send(Event::Connection {
+            source: MessageSource::BleRpc,
+            connected: true,
+            client: desc.conn_handle() as u32,
+        });
         if server.connected_count() < (esp_idf_svc::sys::CONFIG_BT_NIMBLE_MAX_CONNECTIONS as _) {
             log::info!("Multi-connect support: start advertising");
-            advertising.lock().start().unwrap();
+            if let Err(e) = advertising.lock().start() {
+                log::error!("Failed to restart advertising: {e:?}");
+            }
         }
     });
 
-    server.on_disconnect(|desc, reason| {
+    server.on_disconnect(move |desc, reason| {
         log::info!("{desc:?} has left: {reason:?}");
+        // handles get reused, the next client shouldn't pick up where this one left off
+        for reassemblers in &disconnect_reassemblers {
+            reassemblers.lock().remove(&desc.conn_handle());
+        }
+
+        // also drops its event subscriptions
+        let _ = disconnect_events.try_send(Event::Connection {
+            source: MessageSource::BleRpc,
+            connected: false,
+            client: desc.conn_handle() as u32,
+        });
     });
 
     server.on_authentication_complete(|desc, result| {
@@ -64,12 +123,29 @@ pub fn run_ble(engine: RpcRequester) {
         NimbleProperties::READ | NimbleProperties::NOTIFY,
     );
 
-    request_char.lock().on_write(move |args| {
-        let mut slot = req_tx.send_ref().unwrap();
-        slot.buffer.extend_from_slice(args.recv_data());
-        slot.src = MessageSource::BleRpc;
-        // let _ = engine.req_tx.send(args.recv_data().to_vec());
-    });
+    request_char.lock().on_write(rpc_writer(
+        req_tx.clone(),
+        res_tx.clone(),
+        Encoding::Json,
+        json_reassemblers,
+    ));
+
+    let msgpack_request_char = lovense_service.lock().create_characteristic(
+        RPC_MSGPACK_REQ_CHAR,
+        NimbleProperties::WRITE | NimbleProperties::WRITE_NO_RSP,
+    );
+
+    let msgpack_response_char = lovense_service.lock().create_characteristic(
+        RPC_MSGPACK_RES_CHAR,
+        NimbleProperties::READ | NimbleProperties::NOTIFY,
+    );
+
+    msgpack_request_char.lock().on_write(rpc_writer(
+        req_tx,
+        res_tx,
+        Encoding::MsgPack,
+        msgpack_reassemblers,
+    ));
 
     let lovense_rx = lovense_service.lock().create_characteristic(
         LOVENSE_RX_CHAR,
@@ -86,7 +162,11 @@ pub fn run_ble(engine: RpcRequester) {
         .create_characteristic(LOG_CHAR, NimbleProperties::READ | NimbleProperties::NOTIFY);
 
     lovense_rx.lock().on_write(move |args| {
-        let mut slot = lovense_req_tx.send_ref().unwrap();
+        // waiting for room here would hold up the whole BLE stack
+        let Ok(mut slot) = lovense_req_tx.try_send_ref() else {
+            Fault::RequestDropped.record();
+            return;
+        };
         slot.buffer.extend_from_slice(args.recv_data());
         slot.src = MessageSource::BleLovense;
         // let _ = lovense.req_tx.send(args.recv_data().to_vec());
@@ -105,15 +185,152 @@ pub fn run_ble(engine: RpcRequester) {
 
     advertising.lock().start().unwrap();
 
-    loop {
-        let res = res_rx.recv_ref().unwrap();
+    while let Some(res) = res_rx.recv_ref() {
         match res.tag {
-            ResponseTag::Normal => panic!("non-ble response received at ble!"),
+            ResponseTag::Normal => {
+                log::error!("non-ble response received at ble!");
+                Fault::ResponseDropped.record();
+            }
             ResponseTag::Log => log_tx.lock().set_value(&res.buffer).notify(),
             ResponseTag::Lovense => lovense_tx.lock().set_value(&res.buffer).notify(),
-            ResponseTag::BleRpc => response_char.lock().set_value(&res.buffer).notify(),
+            // RPC responses and events only go to the connection they're for
+            ResponseTag::BleRpc => notify_response(
+                server,
+                &mut response_char.lock(),
+                &response_reassemblers[0],
+                &res.buffer,
+                res.correlation as u16,
+            ),
+            ResponseTag::BleMsgPack => notify_response(
+                server,
+                &mut msgpack_response_char.lock(),
+                &response_reassemblers[1],
+                &res.buffer,
+                res.correlation as u16,
+            ),
             ResponseTag::Discard => continue,
         };
     }
 }
+
+/// Queues up RPC requests written to a characteristic, put back together from their fragments.
+/// Messages that can't be put back together get a parse error right away, see [`reject`].
+fn rpc_writer(
+    req_tx: StaticSender<RequestMessage, MessageRecycler>,
+    res_tx: RpcResponder,
+    encoding: Encoding,
+    reassemblers: Reassemblers,
+) -> impl FnMut(&mut OnWriteArgs) + Send + Sync + 'static {
+    move |args| {
+        // each connection sends its own fragments
+        let conn_handle = args.desc().conn_handle();
+        let mut reassemblers = reassemblers.lock();
+        let reassembler = reassemblers.entry(conn_handle).or_default();
+        let message = match reassembler.push(args.recv_data()) {
+            Ok(Some(message)) => message,
+            Ok(None) => return,
+            Err(e) => {
+                log::warn!("Dropped a BLE RPC message: {e}");
+                Fault::Framing.record();
+                reject(&res_tx, encoding, conn_handle, &e);
+                return;
+            }
+        };
+
+        let Ok(mut slot) = req_tx.try_send_ref() else {
+            log::warn!("Request queue is full, dropped a BLE RPC message");
+            Fault::RequestDropped.record();
+            return;
+        };
+        slot.src = MessageSource::BleRpc;
+        slot.encoding = encoding;
+        // the response and the caller's event subscriptions are tied to the connection
+        slot.correlation = conn_handle as u32;
+        slot.buffer.extend_from_slice(message);
+    }
+}
+
+/// Answers a message that never made it to the main loop with a parse error, straight on the
+/// response channel so only the response loop ever notifies. Nothing was called, so it doesn't
+/// count as activity on the session either.
+fn reject(res_tx: &RpcResponder, encoding: Encoding, conn_handle: u16, e: &anyhow::Error) {
+    // waiting for room here would hold up the whole BLE stack
+    let Ok(mut slot) = res_tx.try_send_ref() else {
+        Fault::ResponseDropped.record();
+        return;
+    };
+    slot.tag = match encoding {
+        Encoding::Json => ResponseTag::BleRpc,
+        Encoding::MsgPack => ResponseTag::BleMsgPack,
+    };
+    slot.correlation = conn_handle as u32;
+
+    let res = RpcResponse::invalid(
+        RpcVersion::V2,
+        None,
+        RpcError::new(PARSE_ERROR, format!("Invalid RPC message: {e}")),
+    );
+    if encoding.write(&mut slot.buffer, &res).is_err() {
+        slot.buffer.clear();
+        slot.tag = ResponseTag::Discard;
+    }
+}
+
+/// Largest notification the client on `conn_handle` can take, header included.
+fn fragment_len(server: &BLEServer, conn_handle: u16) -> usize {
+    let mtu = server
+        .connections()
+        .find(|desc| desc.conn_handle() == conn_handle)
+        .map_or(DEFAULT_ATT_MTU, |desc| desc.mtu());
+
+    payload_len(mtu)
+}
+
+fn payload_len(mtu: u16) -> usize {
+    // the ATT notification header takes 3 of those bytes
+    mtu.max(DEFAULT_ATT_MTU) as usize - 3
+}
+
+/// Sends a response or event to `conn_handle`, in fragments unless the client sent its last
+/// request whole.
+fn notify_response(
+    server: &BLEServer,
+    characteristic: &mut BLECharacteristic,
+    reassemblers: &Reassemblers,
+    message: &[u8],
+    conn_handle: u16,
+) {
+    let framed = reassemblers
+        .lock()
+        .get(&conn_handle)
+        .map_or(true, Reassembler::framed);
+
+    if framed {
+        notify_framed(
+            characteristic,
+            message,
+            conn_handle,
+            fragment_len(server, conn_handle),
+        );
+    } else if let Err(e) = characteristic.notify_with(message, conn_handle) {
+        log::warn!("Failed to notify BLE connection {conn_handle}: {e:?}");
+    }
+}
+
+fn notify_framed(
+    characteristic: &mut BLECharacteristic,
+    message: &[u8],
+    conn_handle: u16,
+    fragment_len: usize,
+) {
+    let res = framing::split(message, fragment_len, |fragment| {
+        if let Err(e) = characteristic.notify_with(fragment, conn_handle) {
+            log::warn!("Failed to notify BLE connection {conn_handle}: {e:?}");
+        }
+    });
+
+    if let Err(e) = res {
+        log::error!("Failed to send BLE RPC response: {e}");
+    }
+}
 // }
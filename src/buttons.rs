@@ -0,0 +1,238 @@
+use std::{rc::Rc, time::Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::ConfigType,
+    control::ControlArbiter,
+    gesture::{Gesture, GestureDetector, GestureTimings},
+    hal::wand::Wand,
+    impl_conf_type, impl_rpc_schema,
+    pattern::{self, PatternPlayer},
+    rpc::MessageSource,
+    session::SessionGuard,
+};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ButtonAction {
+    Step { delta: i64 },
+    Preset { percent: i64 },
+    ToggleOff,
+    NextPattern,
+    EmergencyStop,
+}
+
+impl_rpc_schema!(ButtonAction => "action" {
+    "step" { delta: i64 },
+    "preset" { percent: i64 },
+    "toggle_off",
+    "next_pattern",
+    "emergency_stop",
+});
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct GestureBinding {
+    pub button: usize,
+    pub gesture: Gesture,
+    pub action: ButtonAction,
+}
+
+impl_rpc_schema!(GestureBinding => { button: usize, gesture: Gesture, action: ButtonAction });
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ButtonMappings {
+    // what a plain press does, in the order the buttons appear in the panel's BUTTONS: messages
+    pub buttons: [ButtonAction; 3],
+    // actions for any other gesture. these take precedence over `buttons` for presses too
+    #[serde(default)]
+    pub gestures: Vec<GestureBinding>,
+    #[serde(default)]
+    pub timings: GestureTimings,
+}
+
+impl_rpc_schema!(ButtonMappings => {
+    buttons: [ButtonAction; 3],
+    gestures: Vec<GestureBinding>,
+    timings: GestureTimings,
+});
+
+impl Default for ButtonMappings {
+    fn default() -> Self {
+        ButtonMappings {
+            buttons: [
+                ButtonAction::Step { delta: -25 },
+                ButtonAction::Step { delta: 25 },
+                ButtonAction::ToggleOff,
+            ],
+            // hold to ramp up and down
+            gestures: vec![
+                GestureBinding {
+                    button: 0,
+                    gesture: Gesture::Repeat,
+                    action: ButtonAction::Step { delta: -5 },
+                },
+                GestureBinding {
+                    button: 1,
+                    gesture: Gesture::Repeat,
+                    action: ButtonAction::Step { delta: 5 },
+                },
+                GestureBinding {
+                    button: 2,
+                    gesture: Gesture::DoublePress,
+                    action: ButtonAction::NextPattern,
+                },
+            ],
+            timings: GestureTimings::default(),
+        }
+    }
+}
+
+impl ButtonMappings {
+    pub fn action_for(&self, button: usize, gesture: Gesture) -> Option<ButtonAction> {
+        self.gestures
+            .iter()
+            .find(|b| b.button == button && b.gesture == gesture)
+            .map(|b| b.action)
+            .or_else(|| match gesture {
+                Gesture::Press => self.buttons.get(button).copied(),
+                _ => None,
+            })
+    }
+}
+
+impl_conf_type!(ButtonMappings, "/littlefs/buttons.json", BUTTON_MAPPINGS);
+
+pub struct ButtonHandler {
+    pub pwm: Rc<parking_lot::Mutex<Wand>>,
+    pub patterns: Rc<parking_lot::Mutex<PatternPlayer>>,
+    pub control: Rc<parking_lot::Mutex<ControlArbiter>>,
+    pub session: Rc<parking_lot::Mutex<SessionGuard>>,
+    gestures: GestureDetector<3>,
+    // where the last BUTTONS: message came from, only the real panel gets its priority and can
+    // confirm limits
+    source: MessageSource,
+    // level to come back to when toggling back on
+    last_level: i64,
+    // the double press on a toggle button stops the pattern with its first press, so cycling
+    // goes on from the last one played instead of the one playing
+    last_pattern: Option<String>,
+}
+
+impl ButtonHandler {
+    pub fn new(
+        pwm: Rc<parking_lot::Mutex<Wand>>,
+        patterns: Rc<parking_lot::Mutex<PatternPlayer>>,
+        control: Rc<parking_lot::Mutex<ControlArbiter>>,
+        session: Rc<parking_lot::Mutex<SessionGuard>>,
+    ) -> Self {
+        ButtonHandler {
+            pwm,
+            patterns,
+            control,
+            session,
+            gestures: GestureDetector::default(),
+            source: MessageSource::Uart,
+            last_level: 0,
+            last_pattern: None,
+        }
+    }
+
+    /// Feeds in the pressed state of the buttons from a BUTTONS: message sent by `src`.
+    pub fn update(&mut self, pressed: [bool; 3], src: MessageSource, now: Instant) {
+        self.source = src;
+        let mappings = ButtonMappings::read();
+        for event in self.gestures.update(pressed, now, &mappings.timings) {
+            self.handle(&mappings, event.button, event.gesture, now);
+        }
+    }
+
+    /// Fires the gestures that only depend on time passing, like long presses.
+    pub fn tick(&mut self, now: Instant) {
+        let mappings = ButtonMappings::read();
+        for event in self.gestures.tick(now, &mappings.timings) {
+            self.handle(&mappings, event.button, event.gesture, now);
+        }
+    }
+
+    fn handle(&mut self, mappings: &ButtonMappings, button: usize, gesture: Gesture, now: Instant) {
+        self.session.lock().activity(now);
+
+        // a client confirming its own limits with a fake press would defeat the point
+        if gesture == Gesture::Press
+            && self.source == MessageSource::Uart
+            && self.pwm.lock().confirm_pending_limits(now)
+        {
+            return;
+        }
+
+        let Some(action) = mappings.action_for(button, gesture) else {
+            return;
+        };
+
+        if !matches!(action, ButtonAction::EmergencyStop) {
+            if let Err(e) = self.control.lock().check(self.source, now) {
+                log::info!("Ignoring button press: {e}");
+                return;
+            }
+        }
+
+        let mut wand = self.pwm.lock();
+        let mut patterns = self.patterns.lock();
+        let cur = wand.get_percent();
+
+        match action {
+            ButtonAction::Step { delta } => {
+                patterns.stop();
+                wand.set_percent(cur + delta);
+            }
+            ButtonAction::Preset { percent } => {
+                patterns.stop();
+                wand.set_percent(percent);
+            }
+            ButtonAction::ToggleOff => {
+                if cur > 0 || patterns.current().is_some() {
+                    self.last_level = cur;
+                    patterns.stop();
+                    wand.set_percent(0);
+                } else {
+                    wand.set_percent(self.last_level);
+                }
+            }
+            ButtonAction::NextPattern => {
+                let mut names = match pattern::names() {
+                    Ok(names) => names,
+                    Err(e) => {
+                        log::error!("Failed to list patterns: {e}");
+                        return;
+                    }
+                };
+
+                let cur = patterns.current().or(self.last_pattern.as_deref());
+                let next = match cur {
+                    Some(cur) => names
+                        .iter()
+                        .position(|n| n == cur)
+                        .map_or(0, |idx| (idx + 1) % names.len()),
+                    None => 0,
+                };
+
+                let name = names.swap_remove(next);
+                match pattern::load(&name) {
+                    Ok(p) => {
+                        log::info!("Playing pattern {name}");
+                        self.last_pattern = Some(name.clone());
+                        patterns.play(name, p, now);
+                    }
+                    Err(e) => log::error!("Failed to load pattern {name}: {e}"),
+                }
+            }
+            ButtonAction::EmergencyStop => {
+                log::warn!("Emergency stop!");
+                patterns.stop();
+                self.control.lock().clear();
+                wand.stop();
+            }
+        }
+    }
+}
@@ -6,18 +6,13 @@ use serde::{de::DeserializeOwned, Serialize};
 #[macro_export]
 macro_rules! impl_conf_type {
     ($for:path, $path:expr, $store:ident) => {
-        use arc_swap::{ArcSwap, cache::Cache as ArcCache};
-        use std::cell::RefCell;
-        use std::sync::Arc;
-        use std::ops::Deref;
-
         static $store: std::sync::LazyLock<arc_swap::ArcSwap<$for>> =
             std::sync::LazyLock::new(|| arc_swap::ArcSwap::from_pointee(<$for as ConfigType>::load_from_file().unwrap().unwrap_or_default()));
 
 
         impl ConfigType for $for {
             const PATH: &str = $path;
-            thread_local!(static CACHE: RefCell<ArcCache<&'static ArcSwap<$for>, Arc<$for>>> = RefCell::new(ArcCache::from($store.deref())));
+            thread_local!(static CACHE: std::cell::RefCell<arc_swap::cache::Cache<&'static arc_swap::ArcSwap<$for>, std::sync::Arc<$for>>> = std::cell::RefCell::new(arc_swap::cache::Cache::from(std::ops::Deref::deref(&$store))));
 
             fn store(self) -> anyhow::Result<arc_swap::Guard<std::sync::Arc<Self>>> {
                 let f = std::fs::File::create(Self::PATH)?;
@@ -0,0 +1,172 @@
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigType, impl_conf_type, impl_rpc_schema, rpc::MessageSource};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ControlPriorities {
+    pub uart: u8,
+    pub ble_rpc: u8,
+    pub http_rpc: u8,
+    pub ble_lovense: u8,
+}
+
+impl_rpc_schema!(ControlPriorities => { uart: u8, ble_rpc: u8, http_rpc: u8, ble_lovense: u8 });
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ControlConfig {
+    // a source with a higher priority can always break another source's lease
+    pub priorities: ControlPriorities,
+    // lease length in seconds when a client doesn't ask for a specific one
+    pub default_lease: u32,
+}
+
+impl_rpc_schema!(ControlConfig => ControlConfig::default());
+
+impl Default for ControlConfig {
+    fn default() -> Self {
+        ControlConfig {
+            priorities: ControlPriorities {
+                uart: 100,
+                ble_rpc: 50,
+                http_rpc: 50,
+                ble_lovense: 10,
+            },
+            default_lease: 300,
+        }
+    }
+}
+
+impl_conf_type!(ControlConfig, "/littlefs/control.json", CONTROL_CONFIG);
+
+impl ControlConfig {
+    /// The physical buttons always win, no remote source gets to match them.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let p = &self.priorities;
+        if [p.ble_rpc, p.http_rpc, p.ble_lovense]
+            .into_iter()
+            .any(|remote| remote >= p.uart)
+        {
+            bail!("Remote sources need a lower priority than uart.");
+        }
+
+        Ok(())
+    }
+
+    pub fn priority(&self, src: MessageSource) -> u8 {
+        match src {
+            MessageSource::Uart => self.priorities.uart,
+            MessageSource::BleRpc => self.priorities.ble_rpc,
+            MessageSource::HttpRpc | MessageSource::WsRpc => self.priorities.http_rpc,
+            MessageSource::BleLovense => self.priorities.ble_lovense,
+            // can't stand in for the physical buttons
+            MessageSource::Injected | MessageSource::Timer => 0,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct ControlStatus {
+    owner: Option<MessageSource>,
+    remaining: u64,
+    priorities: ControlPriorities,
+}
+
+impl_rpc_schema!(ControlStatus => {
+    owner: Option<MessageSource>,
+    remaining: u64,
+    priorities: ControlPriorities,
+});
+
+struct Lease {
+    owner: MessageSource,
+    until: Instant,
+}
+
+/// Keeps track of which input source currently has exclusive control of the wand.
+/// Without a lease, every source is allowed to drive it.
+#[derive(Default)]
+pub struct ControlArbiter {
+    lease: Option<Lease>,
+}
+
+impl ControlArbiter {
+    fn active_lease(&mut self, now: Instant) -> Option<&Lease> {
+        if self.lease.as_ref().is_some_and(|l| now >= l.until) {
+            self.lease = None;
+        }
+
+        self.lease.as_ref()
+    }
+
+    /// Checks whether `src` may drive the wand right now. A higher priority source breaks the current lease.
+    pub fn check(&mut self, src: MessageSource, now: Instant) -> anyhow::Result<()> {
+        let Some(lease) = self.active_lease(now) else {
+            return Ok(());
+        };
+
+        if lease.owner == src {
+            return Ok(());
+        }
+
+        let config = ControlConfig::read();
+        if config.priority(src) > config.priority(lease.owner) {
+            log::info!(target: "control", "{src:?} overrides the lease held by {:?}", lease.owner);
+            self.lease = None;
+            return Ok(());
+        }
+
+        bail!(
+            "Control is held by {:?} for another {}s.",
+            lease.owner,
+            lease.until.duration_since(now).as_secs()
+        )
+    }
+
+    pub fn acquire(&mut self, src: MessageSource, secs: u32, now: Instant) -> anyhow::Result<()> {
+        self.check(src, now)?;
+
+        let secs = if secs == 0 {
+            ControlConfig::read().default_lease
+        } else {
+            secs
+        };
+
+        self.lease = Some(Lease {
+            owner: src,
+            until: now + Duration::from_secs(secs as u64),
+        });
+
+        Ok(())
+    }
+
+    pub fn release(&mut self, src: MessageSource, now: Instant) -> anyhow::Result<()> {
+        self.check(src, now)?;
+        self.lease = None;
+
+        Ok(())
+    }
+
+    /// Drops the current lease, no matter who holds it.
+    pub fn clear(&mut self) {
+        self.lease = None;
+    }
+
+    pub fn status(&mut self, now: Instant) -> ControlStatus {
+        let (owner, remaining) = match self.active_lease(now) {
+            Some(lease) => (
+                Some(lease.owner),
+                lease.until.duration_since(now).as_secs(),
+            ),
+            None => (None, 0),
+        };
+
+        ControlStatus {
+            owner,
+            remaining,
+            priorities: ControlConfig::read().priorities.clone(),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use serde::Serialize;
+
+use crate::impl_rpc_schema;
+
+/// Ways handling input can go wrong. Each gets counted instead of taking the firmware down,
+/// so they can be looked at with `sys:diagnostics`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Fault {
+    // a request we couldn't make sense of, from any source
+    InvalidRequest,
+    // a request that made sense, but failing to answer it did not
+    HandlerFailed,
+    // a BLE RPC message that couldn't be put back together from its fragments
+    Framing,
+    // a request or response with no channel left to go through
+    RequestDropped,
+    ResponseDropped,
+    // an HTTP request the main loop didn't answer in time
+    Timeout,
+}
+
+impl Fault {
+    const COUNT: usize = 6;
+
+    pub fn record(self) {
+        COUNTERS[self as usize].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+// shared by every thread taking input: the main loop, BLE callbacks and HTTP handlers
+static COUNTERS: [AtomicU32; Fault::COUNT] = [const { AtomicU32::new(0) }; Fault::COUNT];
+
+#[derive(Serialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct Diagnostics {
+    pub invalid_requests: u32,
+    pub handler_failures: u32,
+    pub framing_errors: u32,
+    pub dropped_requests: u32,
+    pub dropped_responses: u32,
+    pub timeouts: u32,
+}
+
+impl_rpc_schema!(Diagnostics => Diagnostics::default());
+
+impl Diagnostics {
+    pub fn read() -> Self {
+        let count = |fault: Fault| COUNTERS[fault as usize].load(Ordering::Relaxed);
+
+        Diagnostics {
+            invalid_requests: count(Fault::InvalidRequest),
+            handler_failures: count(Fault::HandlerFailed),
+            framing_errors: count(Fault::Framing),
+            dropped_requests: count(Fault::RequestDropped),
+            dropped_responses: count(Fault::ResponseDropped),
+            timeouts: count(Fault::Timeout),
+        }
+    }
+
+    pub fn reset() {
+        for counter in &COUNTERS {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
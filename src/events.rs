@@ -0,0 +1,307 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use thingbuf::{
+    mpsc::blocking::{StaticChannel, StaticReceiver},
+    recycling::DefaultRecycle,
+};
+
+use crate::{
+    impl_rpc_schema,
+    rpc::{Encoding, MessageSource, ReplyRoute, ResponseTag, RpcResponder},
+    thermal::ThermalState,
+};
+
+// events from other threads, picked up by the main loop on every tick
+pub static EVENT_QUEUE: StaticChannel<Event, 16, DefaultRecycle> =
+    StaticChannel::<Event, 16, DefaultRecycle>::new();
+
+// a playing pattern moves the intensity every tick, which is way more than clients need to hear about
+const INTENSITY_INTERVAL: Duration = Duration::from_millis(200);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Topic {
+    Intensity,
+    Button,
+    Connection,
+    Thermal,
+    Pd,
+    Ota,
+}
+
+impl_rpc_schema!(Topic => ["intensity", "button", "connection", "thermal", "pd", "ota"]);
+
+impl Topic {
+    pub const ALL: [Topic; 6] = [
+        Topic::Intensity,
+        Topic::Button,
+        Topic::Connection,
+        Topic::Thermal,
+        Topic::Pd,
+        Topic::Ota,
+    ];
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OtaStatus {
+    InProgress,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(tag = "topic", rename_all = "snake_case")]
+pub enum Event {
+    Intensity {
+        target: i64,
+        actual: i64,
+    },
+    Button {
+        index: usize,
+        pressed: bool,
+    },
+    // a BLE client or an `/events` websocket coming or going
+    Connection {
+        source: MessageSource,
+        connected: bool,
+        // the BLE connection handle or the websocket session
+        client: u32,
+    },
+    Thermal {
+        state: ThermalState,
+    },
+    Pd {
+        attached: bool,
+    },
+    Ota {
+        percent: u8,
+        status: OtaStatus,
+    },
+}
+
+impl Default for Event {
+    fn default() -> Self {
+        Event::Intensity {
+            target: 0,
+            actual: 0,
+        }
+    }
+}
+
+impl Event {
+    pub fn topic(&self) -> Topic {
+        match self {
+            Event::Intensity { .. } => Topic::Intensity,
+            Event::Button { .. } => Topic::Button,
+            Event::Connection { .. } => Topic::Connection,
+            Event::Thermal { .. } => Topic::Thermal,
+            Event::Pd { .. } => Topic::Pd,
+            Event::Ota { .. } => Topic::Ota,
+        }
+    }
+
+    /// The event as a JSON-RPC 2.0 notification, which is how clients get it.
+    pub fn notification(&self) -> EventNotification<'_> {
+        EventNotification {
+            jsonrpc: "2.0",
+            method: "sys:event",
+            params: self,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct EventNotification<'a> {
+    jsonrpc: &'static str,
+    method: &'static str,
+    params: &'a Event,
+}
+
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct TopicSet(u8);
+
+impl TopicSet {
+    pub fn contains(self, topic: Topic) -> bool {
+        self.0 & (1 << topic as u8) != 0
+    }
+
+    pub fn topics(self) -> Vec<Topic> {
+        Topic::ALL
+            .into_iter()
+            .filter(|t| self.contains(*t))
+            .collect()
+    }
+}
+
+impl FromIterator<Topic> for TopicSet {
+    fn from_iter<I: IntoIterator<Item = Topic>>(iter: I) -> Self {
+        TopicSet(iter.into_iter().fold(0, |set, t| set | (1 << t as u8)))
+    }
+}
+
+// what a client is subscribed to, and how its events get encoded
+#[derive(Clone, Copy)]
+struct Subscriber {
+    topics: TopicSet,
+    encoding: Encoding,
+}
+
+/// Hands events out to the clients subscribed to them: BLE clients get them as notifications
+/// on the RPC response characteristic they subscribed on, HTTP clients over the `/events`
+/// websocket.
+pub struct EventBus {
+    // per BLE connection handle or websocket session
+    subscribers: HashMap<(MessageSource, u32), Subscriber>,
+    ble_tx: RpcResponder,
+    ws_tx: RpcResponder,
+    // last state we told anyone about, to only publish changes
+    intensity: Option<(i64, bool)>,
+    intensity_at: Option<Instant>,
+    buttons: [bool; 3],
+    thermal: ThermalState,
+    pd_attached: Option<bool>,
+}
+
+impl EventBus {
+    pub fn new(ble_tx: RpcResponder, ws_tx: RpcResponder) -> Self {
+        EventBus {
+            subscribers: HashMap::new(),
+            ble_tx,
+            ws_tx,
+            intensity: None,
+            intensity_at: None,
+            buttons: [false; 3],
+            thermal: ThermalState::Normal,
+            pd_attached: None,
+        }
+    }
+
+    /// Replaces what the client `caller` came from is subscribed to. Its events come in the
+    /// same encoding as its calls.
+    pub fn subscribe(&mut self, caller: ReplyRoute, topics: TopicSet) -> anyhow::Result<()> {
+        match caller.src {
+            MessageSource::BleRpc | MessageSource::WsRpc => {}
+            MessageSource::HttpRpc => {
+                bail!("Over HTTP, send sys:subscribe on the /events websocket instead.")
+            }
+            _ => bail!("Events can only be subscribed to over BLE or HTTP."),
+        }
+
+        let client = (caller.src, caller.correlation);
+        if topics == TopicSet::default() {
+            self.subscribers.remove(&client);
+        } else {
+            let encoding = caller.encoding;
+            self.subscribers
+                .insert(client, Subscriber { topics, encoding });
+        }
+
+        Ok(())
+    }
+
+    pub fn subscriptions(&self, caller: ReplyRoute) -> TopicSet {
+        self.subscribers
+            .get(&(caller.src, caller.correlation))
+            .map_or_else(TopicSet::default, |s| s.topics)
+    }
+
+    pub fn publish(&mut self, event: Event) {
+        let topic = event.topic();
+
+        for (&(src, client), subscriber) in &self.subscribers {
+            if !subscriber.topics.contains(topic) {
+                continue;
+            }
+
+            let (tx, tag) = match (src, subscriber.encoding) {
+                (MessageSource::BleRpc, Encoding::Json) => (&self.ble_tx, ResponseTag::BleRpc),
+                (MessageSource::BleRpc, Encoding::MsgPack) => {
+                    (&self.ble_tx, ResponseTag::BleMsgPack)
+                }
+                _ => (&self.ws_tx, ResponseTag::Normal),
+            };
+
+            match tx.try_send_ref() {
+                Ok(mut slot) => {
+                    slot.tag = tag;
+                    slot.correlation = client;
+                    let res = subscriber
+                        .encoding
+                        .write(&mut slot.buffer, &event.notification());
+                    if let Err(e) = res {
+                        log::error!("Failed to serialize event: {e}");
+                        slot.buffer.clear();
+                        slot.tag = ResponseTag::Discard;
+                    }
+                }
+                Err(_) => log::warn!("{src:?} is falling behind, dropped a {topic:?} event"),
+            }
+        }
+    }
+
+    /// Publishes everything other threads sent our way since the last call.
+    pub fn drain(&mut self, rx: &StaticReceiver<Event, DefaultRecycle>) {
+        while let Ok(event) = rx.try_recv() {
+            // handles and sessions get reused, the next client starts out without subscriptions
+            if let Event::Connection {
+                source,
+                connected: false,
+                client,
+            } = event
+            {
+                self.subscribers.remove(&(source, client));
+            }
+
+            self.publish(event);
+        }
+    }
+
+    /// Publishes when the target changes or the wand catches up with it, at most every
+    /// `INTENSITY_INTERVAL`.
+    pub fn intensity(&mut self, now: Instant, target: i64, actual: i64) {
+        let state = (target, actual == target);
+        if self.intensity == Some(state) {
+            return;
+        }
+
+        if self
+            .intensity_at
+            .is_some_and(|at| now.duration_since(at) < INTENSITY_INTERVAL)
+        {
+            return;
+        }
+
+        self.intensity = Some(state);
+        self.intensity_at = Some(now);
+        self.publish(Event::Intensity { target, actual });
+    }
+
+    pub fn buttons(&mut self, pressed: [bool; 3]) {
+        let before = std::mem::replace(&mut self.buttons, pressed);
+        for (index, (was, is)) in before.into_iter().zip(pressed).enumerate() {
+            if was != is {
+                self.publish(Event::Button { index, pressed: is });
+            }
+        }
+    }
+
+    pub fn thermal(&mut self, state: ThermalState) {
+        if self.thermal != state {
+            self.thermal = state;
+            self.publish(Event::Thermal { state });
+        }
+    }
+
+    pub fn pd(&mut self, attached: bool) {
+        if self.pd_attached != Some(attached) {
+            self.pd_attached = Some(attached);
+            self.publish(Event::Pd { attached });
+        }
+    }
+}
@@ -0,0 +1,158 @@
+use anyhow::bail;
+
+// Every fragment of a BLE RPC message starts with a 4 byte header:
+// `0xF0 | MORE`, the fragment's sequence number within the message (wrapping), and the total
+// length of the message as a little endian u16. `MORE` is set on every fragment but the last.
+// Writes not starting with the marker are taken as whole messages, like older clients send them,
+// and those clients get their answers whole too.
+pub const HEADER_LEN: usize = 4;
+const MARKER: u8 = 0xF0;
+const MORE: u8 = 0x01;
+
+pub const MAX_MESSAGE_LEN: usize = 8192;
+
+pub fn is_framed(data: &[u8]) -> bool {
+    data.first().is_some_and(|b| b & !MORE == MARKER)
+}
+
+/// Puts a message written to a characteristic in fragments back together.
+#[derive(Default)]
+pub struct Reassembler {
+    buffer: Vec<u8>,
+    total: usize,
+    next_seq: u8,
+    in_progress: bool,
+    // whether the last message came in fragments, answers go back the same way
+    framed: bool,
+}
+
+impl Reassembler {
+    /// Feeds one write to the reassembler, returning the whole message once its last fragment is in.
+    /// A broken message is dropped, and the next one starts over from its first fragment.
+    pub fn push(&mut self, fragment: &[u8]) -> anyhow::Result<Option<&[u8]>> {
+        self.framed = is_framed(fragment);
+        if !self.framed {
+            self.in_progress = false;
+            self.buffer.clear();
+            self.buffer.extend_from_slice(fragment);
+            return Ok(Some(&self.buffer));
+        }
+
+        let res = self.push_fragment(fragment);
+        if res.is_err() {
+            self.in_progress = false;
+            self.buffer.clear();
+        }
+
+        match res? {
+            true => Ok(Some(&self.buffer)),
+            false => Ok(None),
+        }
+    }
+
+    /// Whether the client fragments its messages, older ones send and expect them whole.
+    pub fn framed(&self) -> bool {
+        self.framed
+    }
+
+    fn push_fragment(&mut self, fragment: &[u8]) -> anyhow::Result<bool> {
+        let Some((header, payload)) = fragment.split_first_chunk::<HEADER_LEN>() else {
+            bail!("Fragment is shorter than its header.");
+        };
+
+        let more = header[0] & MORE != 0;
+        let seq = header[1];
+        let total = u16::from_le_bytes([header[2], header[3]]) as usize;
+
+        // once past 255 fragments a 0 is the sequence wrapping around, not a new message
+        let continues = self.in_progress && !(seq == 0 && self.next_seq != 0);
+        if !continues {
+            if seq != 0 {
+                bail!("Fragment {seq} came in without the start of its message.");
+            }
+
+            if total > MAX_MESSAGE_LEN {
+                bail!("Message of {total} bytes is over the limit of {MAX_MESSAGE_LEN}.");
+            }
+
+            self.buffer.clear();
+            self.total = total;
+            self.in_progress = true;
+        } else if seq != self.next_seq || total != self.total {
+            bail!(
+                "Fragment {seq} is out of order, expected {}.",
+                self.next_seq
+            );
+        }
+
+        if self.buffer.len() + payload.len() > self.total {
+            bail!(
+                "Fragments add up to more than the {} bytes announced.",
+                self.total
+            );
+        }
+
+        self.buffer.extend_from_slice(payload);
+        self.next_seq = seq.wrapping_add(1);
+
+        if more {
+            return Ok(false);
+        }
+
+        self.in_progress = false;
+        self.next_seq = 0;
+        if self.buffer.len() != self.total {
+            bail!(
+                "Message ended after {} bytes, {} were announced.",
+                self.buffer.len(),
+                self.total
+            );
+        }
+
+        Ok(true)
+    }
+}
+
+/// Splits a message into fragments of at most `fragment_len` bytes, header included,
+/// handing each one to `send`.
+pub fn split(
+    message: &[u8],
+    fragment_len: usize,
+    mut send: impl FnMut(&[u8]),
+) -> anyhow::Result<()> {
+    if message.len() > u16::MAX as usize {
+        bail!("Message of {} bytes is too long to send.", message.len());
+    }
+
+    if fragment_len <= HEADER_LEN {
+        bail!("Fragments of {fragment_len} bytes leave no room for a payload.");
+    }
+
+    let total = (message.len() as u16).to_le_bytes();
+    let mut chunks = message.chunks(fragment_len - HEADER_LEN).peekable();
+    let mut fragment = Vec::with_capacity(fragment_len);
+    let mut seq = 0u8;
+
+    // an empty message still goes out as a single empty fragment
+    let mut chunk = chunks.next().unwrap_or_default();
+    loop {
+        let more = chunks.peek().is_some();
+
+        fragment.clear();
+        fragment.extend_from_slice(&[
+            MARKER | if more { MORE } else { 0 },
+            seq,
+            total[0],
+            total[1],
+        ]);
+        fragment.extend_from_slice(chunk);
+        send(&fragment);
+
+        match chunks.next() {
+            Some(next) => chunk = next,
+            None => return Ok(()),
+        }
+
+        seq = seq.wrapping_add(1);
+    }
+}
@@ -0,0 +1,264 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::impl_rpc_schema;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Gesture {
+    Press,
+    Release,
+    LongPress,
+    // fires on the second press, in addition to its `Press`
+    DoublePress,
+    // fires periodically while a button stays held after a long press
+    Repeat,
+}
+
+impl_rpc_schema!(Gesture => ["press", "release", "long_press", "double_press", "repeat"]);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct GestureTimings {
+    pub long_press_ms: u32,
+    pub double_press_ms: u32,
+    pub repeat_ms: u32,
+}
+
+impl_rpc_schema!(GestureTimings => GestureTimings::default());
+
+impl Default for GestureTimings {
+    fn default() -> Self {
+        GestureTimings {
+            long_press_ms: 600,
+            double_press_ms: 300,
+            repeat_ms: 150,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct GestureEvent {
+    pub button: usize,
+    pub gesture: Gesture,
+}
+
+#[derive(Clone, Copy, Default)]
+struct ButtonState {
+    pressed_at: Option<Instant>,
+    last_release: Option<Instant>,
+    long_fired: bool,
+    // the current press already completed a double press
+    double_fired: bool,
+    last_repeat: Option<Instant>,
+}
+
+/// Turns the raw pressed/released states coming from the panel into gestures.
+/// Doesn't depend on anything hardware specific, time is always passed in.
+pub struct GestureDetector<const N: usize> {
+    buttons: [ButtonState; N],
+}
+
+impl<const N: usize> Default for GestureDetector<N> {
+    fn default() -> Self {
+        GestureDetector {
+            buttons: [ButtonState::default(); N],
+        }
+    }
+}
+
+impl<const N: usize> GestureDetector<N> {
+    /// Feeds in the latest pressed state of every button.
+    pub fn update(
+        &mut self,
+        pressed: [bool; N],
+        now: Instant,
+        timings: &GestureTimings,
+    ) -> Vec<GestureEvent> {
+        let mut events = Vec::new();
+        let double_press = Duration::from_millis(timings.double_press_ms as u64);
+
+        for (button, (state, pressed)) in self.buttons.iter_mut().zip(pressed).enumerate() {
+            match (state.pressed_at.is_some(), pressed) {
+                (false, true) => {
+                    events.push(GestureEvent {
+                        button,
+                        gesture: Gesture::Press,
+                    });
+
+                    state.double_fired = state
+                        .last_release
+                        .is_some_and(|at| now.duration_since(at) <= double_press);
+                    if state.double_fired {
+                        events.push(GestureEvent {
+                            button,
+                            gesture: Gesture::DoublePress,
+                        });
+                    }
+
+                    state.pressed_at = Some(now);
+                    state.long_fired = false;
+                    state.last_repeat = None;
+                }
+                (true, false) => {
+                    events.push(GestureEvent {
+                        button,
+                        gesture: Gesture::Release,
+                    });
+
+                    // releasing a long press shouldn't count towards a double press, and a third
+                    // tap starts over instead of being another double press
+                    state.last_release = (!state.long_fired && !state.double_fired).then_some(now);
+                    state.pressed_at = None;
+                }
+                _ => {}
+            }
+        }
+
+        events.extend(self.tick(now, timings));
+        events
+    }
+
+    /// Emits the time based gestures for buttons that are being held.
+    pub fn tick(&mut self, now: Instant, timings: &GestureTimings) -> Vec<GestureEvent> {
+        let mut events = Vec::new();
+        let long_press = Duration::from_millis(timings.long_press_ms as u64);
+        let repeat = Duration::from_millis(timings.repeat_ms as u64);
+
+        for (button, state) in self.buttons.iter_mut().enumerate() {
+            let Some(pressed_at) = state.pressed_at else {
+                continue;
+            };
+
+            if now.duration_since(pressed_at) < long_press {
+                continue;
+            }
+
+            if !state.long_fired {
+                state.long_fired = true;
+                state.last_repeat = Some(now);
+                events.push(GestureEvent {
+                    button,
+                    gesture: Gesture::LongPress,
+                });
+            } else if state
+                .last_repeat
+                .is_some_and(|at| now.duration_since(at) >= repeat)
+            {
+                state.last_repeat = Some(now);
+                events.push(GestureEvent {
+                    button,
+                    gesture: Gesture::Repeat,
+                });
+            }
+        }
+
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UP: [bool; 2] = [false, false];
+    const DOWN: [bool; 2] = [true, false];
+
+    fn at(start: Instant, ms: u64) -> Instant {
+        start + Duration::from_millis(ms)
+    }
+
+    fn gestures(events: Vec<GestureEvent>) -> Vec<(usize, Gesture)> {
+        events.into_iter().map(|e| (e.button, e.gesture)).collect()
+    }
+
+    #[test]
+    fn press_and_release() {
+        let start = Instant::now();
+        let timings = GestureTimings::default();
+        let mut detector = GestureDetector::<2>::default();
+
+        let events = detector.update([false, true], start, &timings);
+        assert_eq!(gestures(events), [(1, Gesture::Press)]);
+
+        // nothing changed, nothing to report
+        let events = detector.update([false, true], at(start, 50), &timings);
+        assert!(events.is_empty());
+
+        let events = detector.update(UP, at(start, 100), &timings);
+        assert_eq!(gestures(events), [(1, Gesture::Release)]);
+    }
+
+    #[test]
+    fn long_press_then_repeat() {
+        let start = Instant::now();
+        let timings = GestureTimings::default();
+        let mut detector = GestureDetector::<2>::default();
+
+        detector.update(DOWN, start, &timings);
+        assert!(detector.tick(at(start, 599), &timings).is_empty());
+
+        let events = detector.tick(at(start, 600), &timings);
+        assert_eq!(gestures(events), [(0, Gesture::LongPress)]);
+
+        // repeats come every `repeat_ms` after the long press, not on every tick
+        assert!(detector.tick(at(start, 700), &timings).is_empty());
+        let events = detector.tick(at(start, 750), &timings);
+        assert_eq!(gestures(events), [(0, Gesture::Repeat)]);
+        assert!(detector.tick(at(start, 850), &timings).is_empty());
+        let events = detector.tick(at(start, 900), &timings);
+        assert_eq!(gestures(events), [(0, Gesture::Repeat)]);
+
+        let events = detector.update(UP, at(start, 920), &timings);
+        assert_eq!(gestures(events), [(0, Gesture::Release)]);
+        assert!(detector.tick(at(start, 2000), &timings).is_empty());
+    }
+
+    #[test]
+    fn double_press_within_window() {
+        let start = Instant::now();
+        let timings = GestureTimings::default();
+        let mut detector = GestureDetector::<2>::default();
+
+        detector.update(DOWN, start, &timings);
+        detector.update(UP, at(start, 100), &timings);
+
+        let events = detector.update(DOWN, at(start, 400), &timings);
+        assert_eq!(
+            gestures(events),
+            [(0, Gesture::Press), (0, Gesture::DoublePress)]
+        );
+
+        // a third tap starts over
+        detector.update(UP, at(start, 450), &timings);
+        let events = detector.update(DOWN, at(start, 500), &timings);
+        assert_eq!(gestures(events), [(0, Gesture::Press)]);
+    }
+
+    #[test]
+    fn double_press_window_expires() {
+        let start = Instant::now();
+        let timings = GestureTimings::default();
+        let mut detector = GestureDetector::<2>::default();
+
+        detector.update(DOWN, start, &timings);
+        detector.update(UP, at(start, 100), &timings);
+
+        let events = detector.update(DOWN, at(start, 401), &timings);
+        assert_eq!(gestures(events), [(0, Gesture::Press)]);
+    }
+
+    #[test]
+    fn long_press_release_is_not_half_a_double_press() {
+        let start = Instant::now();
+        let timings = GestureTimings::default();
+        let mut detector = GestureDetector::<2>::default();
+
+        detector.update(DOWN, start, &timings);
+        detector.tick(at(start, 600), &timings);
+        detector.update(UP, at(start, 650), &timings);
+
+        let events = detector.update(DOWN, at(start, 700), &timings);
+        assert_eq!(gestures(events), [(0, Gesture::Press)]);
+    }
+}
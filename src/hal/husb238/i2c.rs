@@ -16,6 +16,8 @@ use mycelium_bitfield::enum_from_bits;
 use registers::GO_COMMAND;
 use serde::Serialize;
 
+use crate::impl_rpc_schema;
+
 const HUSB238_ADDR: u8 = 0x08;
 
 enum_from_bits! {
@@ -43,6 +45,8 @@ enum_from_bits! {
     }
 }
 
+impl_rpc_schema!(SrcVoltage => ["Unattached", "PD5V", "PD9V", "PD12V", "PD15V", "PD18V", "PD20V"]);
+
 impl From<SrcVoltage> for f64 {
     fn from(value: SrcVoltage) -> Self {
         match value {
@@ -79,6 +83,11 @@ enum_from_bits! {
     }
 }
 
+impl_rpc_schema!(Current => [
+    "PD0_50", "PD0_70", "PD1_00", "PD1_25", "PD1_50", "PD1_75", "PD2_00", "PD2_25", "PD2_50",
+    "PD2_75", "PD3_00", "PD3_25", "PD3_50", "PD4_00", "PD4_50", "PD5_00"
+]);
+
 impl From<Current> for f64 {
     fn from(value: Current) -> Self {
         match value {
@@ -134,6 +143,8 @@ enum_from_bits! {
     }
 }
 
+impl_rpc_schema!(Current5V => ["Default", "Current1_5", "Current2_4", "Current3_0"]);
+
 enum_from_bits! {
     #[derive(Debug, PartialEq, Serialize)]
     pub enum PdResponse<u8> {
@@ -145,6 +156,14 @@ enum_from_bits! {
     }
 }
 
+impl_rpc_schema!(PdResponse => [
+    "NoResponse",
+    "Success",
+    "InvalidCommand",
+    "CommandNotSupported",
+    "TransactionFailed"
+]);
+
 #[allow(non_camel_case_types)]
 pub(super) mod registers {
     use super::*;
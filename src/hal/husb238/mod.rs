@@ -7,6 +7,8 @@ pub mod i2c;
 pub use i2c::Husb238Driver;
 use serde::Serialize;
 
+use crate::impl_rpc_schema;
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Status {
     pub selected_voltage: SrcVoltage,
@@ -17,6 +19,15 @@ pub struct Status {
     pub current_5v: Option<Current5V>,
 }
 
+impl_rpc_schema!(Status => {
+    selected_voltage: SrcVoltage,
+    selected_current: Current,
+    attached: bool,
+    cc2_attached: bool,
+    pd_response: PdResponse,
+    current_5v: Option<Current5V>,
+});
+
 #[derive(Clone, Debug, Serialize)]
 pub struct Capabilities {
     pub pdo_5v: Option<Current>,
@@ -27,6 +38,15 @@ pub struct Capabilities {
     pub pdo_20v: Option<Current>,
 }
 
+impl_rpc_schema!(Capabilities => {
+    pdo_5v: Option<Current>,
+    pdo_9v: Option<Current>,
+    pdo_12v: Option<Current>,
+    pdo_15v: Option<Current>,
+    pdo_18v: Option<Current>,
+    pdo_20v: Option<Current>,
+});
+
 impl Husb238Driver {
     pub fn get_status(&mut self) -> anyhow::Result<Status> {
         let status0: PD_STATUS0 = self.read_register(PD_STATUS0::ADDR)?;
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+use super::wand::Lights;
+
+/// Short light sequences for system events, played over whatever the wand is showing.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Animation {
+    // loops until stopped, e.g. while wifi connects
+    Chase,
+    // a few quick blinks, e.g. when a BLE client connects
+    Blink,
+    // a progress bar held until stopped or replaced, 0..=100
+    Progress(u8),
+    // loops until stopped, takes precedence over every other animation
+    Warning,
+    // fast blinks for something that went wrong
+    Error,
+}
+
+const CHASE_STEP: Duration = Duration::from_millis(120);
+const BLINK_STEP: Duration = Duration::from_millis(150);
+const BLINK_COUNT: u32 = 3;
+const WARNING_STEP: Duration = Duration::from_millis(250);
+const ERROR_STEP: Duration = Duration::from_millis(80);
+const ERROR_COUNT: u32 = 5;
+
+impl Animation {
+    // an animation can't be replaced by one of lower priority
+    fn priority(&self) -> u8 {
+        match self {
+            Animation::Warning => 2,
+            Animation::Error => 1,
+            _ => 0,
+        }
+    }
+
+    /// The lights to show `elapsed` into the animation, along with how long they should stay up.
+    /// Returns `None` once the animation is over.
+    pub fn frame(&self, elapsed: Duration) -> Option<(Lights, Option<Duration>)> {
+        let frame = match self {
+            Animation::Chase => {
+                let step = elapsed.as_millis() / CHASE_STEP.as_millis();
+                (
+                    Lights::single(step as usize % 4),
+                    Some(until_next(elapsed, CHASE_STEP)),
+                )
+            }
+            Animation::Blink => Self::blink(elapsed, BLINK_STEP, BLINK_COUNT)?,
+            Animation::Progress(pct) => (Lights::from_mapping(*pct as i64, &[0, 25, 50, 75]), None),
+            Animation::Warning => {
+                let outer = elapsed.as_millis() / WARNING_STEP.as_millis() % 2 == 0;
+                let lights = Lights {
+                    mid_low: !outer,
+                    mid_high: !outer,
+                    top: outer,
+                    bottom: outer,
+                };
+
+                (lights, Some(until_next(elapsed, WARNING_STEP)))
+            }
+            Animation::Error => Self::blink(elapsed, ERROR_STEP, ERROR_COUNT)?,
+        };
+
+        Some(frame)
+    }
+
+    fn blink(elapsed: Duration, step: Duration, count: u32) -> Option<(Lights, Option<Duration>)> {
+        let idx = elapsed.as_millis() / step.as_millis();
+        if idx >= (count * 2) as u128 {
+            return None;
+        }
+
+        Some((Lights::all(idx % 2 == 0), Some(until_next(elapsed, step))))
+    }
+}
+
+fn until_next(elapsed: Duration, step: Duration) -> Duration {
+    let into = elapsed.as_millis() % step.as_millis();
+    step - Duration::from_millis(into as u64)
+}
+
+pub type RawFrame = heapless::Vec<u8, 64>;
+
+#[derive(Clone, Debug)]
+pub enum LightCommand {
+    // what the lights show when no animation is playing
+    Set(Lights),
+    Play(Animation),
+    // stops the animation if it's the one playing, going back to the last `Set`.
+    // the payload of `Progress` doesn't matter
+    Stop(Animation),
+    // bytes written to the panel as they are, leaving the lights alone
+    Raw(RawFrame),
+}
+
+impl Default for LightCommand {
+    fn default() -> Self {
+        LightCommand::Set(Lights::default())
+    }
+}
+
+/// Keeps track of the base lights and the animation playing on top of them.
+#[derive(Default)]
+pub struct Animator {
+    base: Lights,
+    playing: Option<(Animation, Instant)>,
+}
+
+impl Animator {
+    pub fn handle(&mut self, cmd: LightCommand, now: Instant) {
+        match cmd {
+            LightCommand::Set(lights) => self.base = lights,
+            LightCommand::Play(animation) => {
+                if self
+                    .playing
+                    .is_some_and(|(playing, _)| playing.priority() > animation.priority())
+                {
+                    return;
+                }
+
+                self.playing = Some((animation, now));
+            }
+            LightCommand::Stop(animation) => {
+                // so e.g. wifi coming up can't hide an overheating warning
+                if self.playing.is_some_and(|(playing, _)| {
+                    std::mem::discriminant(&playing) == std::mem::discriminant(&animation)
+                }) {
+                    self.playing = None;
+                }
+            }
+            LightCommand::Raw(_) => {}
+        }
+    }
+
+    /// What the lights should be showing right now, and how long until that might change.
+    pub fn frame(&mut self, now: Instant) -> (Lights, Option<Duration>) {
+        if let Some((animation, started_at)) = self.playing {
+            match animation.frame(now.duration_since(started_at)) {
+                Some(frame) => return frame,
+                None => self.playing = None,
+            }
+        }
+
+        (self.base, None)
+    }
+}
@@ -1,5 +1,7 @@
 #[cfg(feature = "usb_pd")]
 pub mod husb238;
+pub mod lights;
+pub mod panel;
+pub mod timer;
 pub mod uart;
 pub mod wand;
-// pub mod timer;
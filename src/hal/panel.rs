@@ -0,0 +1,203 @@
+use anyhow::{anyhow, bail};
+use serde::Serialize;
+
+use crate::impl_rpc_schema;
+
+// distinct unknown lines we hold on to, so a chatty panel can't eat all the memory
+const MAX_UNKNOWN: usize = 16;
+
+/// A line sent by the front panel over UART.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PanelMessage {
+    // `BUTTONS:xyz`, one digit per button, 0 means pressed
+    Buttons([bool; 3]),
+    // anything we don't know how to read yet
+    Unknown(String),
+}
+
+impl PanelMessage {
+    pub fn parse(line: &[u8]) -> anyhow::Result<Self> {
+        let line = std::str::from_utf8(line)
+            .map_err(|e| anyhow!("Panel sent invalid UTF-8: {e}"))?
+            .trim();
+
+        if line.is_empty() {
+            bail!("Panel sent an empty line.");
+        }
+
+        let Some((kind, payload)) = line.split_once(':') else {
+            return Ok(PanelMessage::Unknown(line.to_owned()));
+        };
+
+        match kind {
+            "BUTTONS" => Ok(PanelMessage::Buttons(parse_buttons(payload)?)),
+            _ => Ok(PanelMessage::Unknown(line.to_owned())),
+        }
+    }
+}
+
+fn parse_buttons(payload: &str) -> anyhow::Result<[bool; 3]> {
+    // anything after the 3 buttons we know about is left for panel revisions that send more
+    let Some(bytes) = payload.as_bytes().first_chunk::<3>() else {
+        bail!("BUTTONS: expected 3 states, got {:?}", payload);
+    };
+
+    let mut pressed = [false; 3];
+    for (state, b) in pressed.iter_mut().zip(bytes) {
+        *state = match b {
+            b'0' => true,
+            b'1' => false,
+            _ => bail!("BUTTONS: invalid state {:?}", payload),
+        };
+    }
+
+    Ok(pressed)
+}
+
+#[derive(Serialize, Clone, Copy, Default)]
+pub struct PanelStats {
+    pub received: u32,
+    pub malformed: u32,
+    pub unknown: u32,
+}
+
+impl_rpc_schema!(PanelStats => PanelStats::default());
+
+#[derive(Serialize, Clone)]
+pub struct UnknownMessage {
+    pub line: String,
+    pub count: u32,
+}
+
+impl_rpc_schema!(UnknownMessage => { line: String, count: u32 });
+
+/// Parses the lines coming from the panel, keeping count of what went wrong along the way.
+#[derive(Default)]
+pub struct PanelMonitor {
+    stats: PanelStats,
+    unknown: Vec<UnknownMessage>,
+}
+
+impl PanelMonitor {
+    /// Returns the parsed message, or `None` if the line was blank or malformed.
+    pub fn handle(&mut self, line: &[u8]) -> Option<PanelMessage> {
+        if line.trim_ascii().is_empty() {
+            return None;
+        }
+
+        self.stats.received += 1;
+
+        let message = match PanelMessage::parse(line) {
+            Ok(message) => message,
+            Err(e) => {
+                self.stats.malformed += 1;
+                log::warn!(target: "panel", "{e}");
+                return None;
+            }
+        };
+
+        if let PanelMessage::Unknown(ref line) = message {
+            self.stats.unknown += 1;
+            if let Some(seen) = self.unknown.iter_mut().find(|u| &u.line == line) {
+                seen.count += 1;
+            } else if self.unknown.len() < MAX_UNKNOWN {
+                log::info!(target: "panel", "Unknown message: {line}");
+                self.unknown.push(UnknownMessage {
+                    line: line.clone(),
+                    count: 1,
+                });
+            }
+        }
+
+        Some(message)
+    }
+
+    pub fn stats(&self) -> PanelStats {
+        self.stats
+    }
+
+    pub fn unknown(&self) -> &[UnknownMessage] {
+        &self.unknown
+    }
+
+    pub fn clear_unknown(&mut self) {
+        self.unknown.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buttons() {
+        assert_eq!(
+            PanelMessage::parse(b"BUTTONS:010\r\n").unwrap(),
+            PanelMessage::Buttons([true, false, true])
+        );
+        assert_eq!(
+            PanelMessage::parse(b"BUTTONS:111").unwrap(),
+            PanelMessage::Buttons([false, false, false])
+        );
+    }
+
+    #[test]
+    fn long_buttons_keep_the_first_three() {
+        for line in [&b"BUTTONS:0110"[..], b"BUTTONS:011:1", b"BUTTONS:011 extra"] {
+            assert_eq!(
+                PanelMessage::parse(line).unwrap(),
+                PanelMessage::Buttons([true, false, false]),
+                "line {:?}",
+                std::str::from_utf8(line)
+            );
+        }
+    }
+
+    #[test]
+    fn short_buttons() {
+        for line in [&b"BUTTONS:"[..], b"BUTTONS:0", b"BUTTONS:01"] {
+            assert!(PanelMessage::parse(line).is_err());
+        }
+    }
+
+    #[test]
+    fn non_digit_buttons() {
+        for line in [&b"BUTTONS:0x1"[..], b"BUTTONS:abc", b"BUTTONS:2 0"] {
+            assert!(PanelMessage::parse(line).is_err());
+        }
+
+        assert!(PanelMessage::parse(b"BUTTONS:01\xff").is_err());
+    }
+
+    #[test]
+    fn unknown_lines() {
+        assert_eq!(
+            PanelMessage::parse(b"VERSION:1.2").unwrap(),
+            PanelMessage::Unknown("VERSION:1.2".to_owned())
+        );
+        assert_eq!(
+            PanelMessage::parse(b" hello \n").unwrap(),
+            PanelMessage::Unknown("hello".to_owned())
+        );
+    }
+
+    #[test]
+    fn monitor_counts() {
+        let mut monitor = PanelMonitor::default();
+
+        // blank lines aren't messages at all
+        assert_eq!(monitor.handle(b"\r\n"), None);
+        assert_eq!(monitor.handle(b"BUTTONS:01"), None);
+        assert!(monitor.handle(b"BUTTONS:000").is_some());
+        monitor.handle(b"HELLO");
+        monitor.handle(b"HELLO");
+
+        let stats = monitor.stats();
+        assert_eq!((stats.received, stats.malformed, stats.unknown), (4, 1, 2));
+
+        // the same unknown line is only kept once
+        let unknown = monitor.unknown();
+        assert_eq!(unknown.len(), 1);
+        assert_eq!((unknown[0].line.as_str(), unknown[0].count), ("HELLO", 2));
+    }
+}
@@ -0,0 +1,30 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::rpc::{MessageRecycler, MessageSource, RequestMessage};
+
+pub const TICK_INTERVAL: Duration = Duration::from_millis(20);
+
+// only one tick is ever waiting in the request queue, so a busy main loop doesn't get flooded
+pub static TICK_PENDING: AtomicBool = AtomicBool::new(false);
+
+pub fn spawn_timer_thread(
+    req_tx: StaticSender<RequestMessage, MessageRecycler>,
+) -> std::thread::JoinHandle<()> {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(TICK_INTERVAL);
+
+        if TICK_PENDING.swap(true, Ordering::AcqRel) {
+            continue;
+        }
+
+        match req_tx.try_send_ref() {
+            Ok(mut slot) => slot.src = MessageSource::Timer,
+            Err(_) => TICK_PENDING.store(false, Ordering::Release),
+        }
+    })
+}
@@ -1,64 +1,324 @@
 use core::str;
-use std::{collections::VecDeque, str::FromStr, sync::Arc};
+use std::{collections::VecDeque, sync::Arc, time::Instant};
 
+use anyhow::bail;
 use arrayvec::ArrayString;
-use esp_idf_hal::{delay::BLOCK, io::Write, task::queue::Queue, uart::UartDriver};
+use esp_idf_hal::{
+    delay::BLOCK,
+    io::Write,
+    sys::{
+        esp, esp_timer_get_time, uart_parity_t, uart_parity_t_UART_PARITY_DISABLE,
+        uart_parity_t_UART_PARITY_EVEN, uart_parity_t_UART_PARITY_ODD, uart_port_t,
+        uart_set_baudrate, uart_set_parity, uart_set_stop_bits, uart_stop_bits_t,
+        uart_stop_bits_t_UART_STOP_BITS_1, uart_stop_bits_t_UART_STOP_BITS_1_5,
+        uart_stop_bits_t_UART_STOP_BITS_2,
+    },
+    task::queue::Queue,
+    uart::{config, UartDriver},
+    units::Hertz,
+};
 use memchr::memchr_iter;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
 use thingbuf::{
-    mpsc::blocking::{StaticChannel, StaticSender},
+    mpsc::{
+        blocking::{StaticChannel, StaticSender},
+        errors::RecvTimeoutError,
+    },
     recycling::DefaultRecycle,
 };
 
-use crate::rpc::{MessageSource, RpcRequester};
+use crate::{
+    config::ConfigType,
+    diagnostics::Fault,
+    impl_conf_type, impl_rpc_schema,
+    rpc::{MessageSource, RpcRequester},
+};
+
+use super::lights::{Animator, LightCommand};
+
+pub static UART_QUEUE: StaticChannel<LightCommand, 32, DefaultRecycle> =
+    StaticChannel::<LightCommand, 32, DefaultRecycle>::new();
+
+// the panel sits on UART1
+const PANEL_UART: uart_port_t = 1;
+
+// panel lines are a handful of bytes, anything this long without a newline is garbage
+const MAX_LINE_LEN: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Parity {
+    None,
+    Even,
+    Odd,
+}
+
+impl_rpc_schema!(Parity => ["none", "even", "odd"]);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StopBits {
+    One,
+    OneAndHalf,
+    Two,
+}
+
+impl_rpc_schema!(StopBits => ["one", "one_and_half", "two"]);
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct UartBusConfig {
+    pub baud_rate: u32,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+}
+
+impl_rpc_schema!(UartBusConfig => { baud_rate: u32, parity: Parity, stop_bits: StopBits });
+
+impl Default for UartBusConfig {
+    fn default() -> Self {
+        UartBusConfig {
+            baud_rate: 9600,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl_conf_type!(UartBusConfig, "/littlefs/uart.json", UART_BUS_CONFIG);
+
+impl UartBusConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1200..=1_000_000).contains(&self.baud_rate) {
+            bail!("Baud rate {} is outside of 1200..=1000000.", self.baud_rate);
+        }
+
+        Ok(())
+    }
+
+    /// Config for bringing up the driver at boot.
+    pub fn driver_config(&self) -> config::Config {
+        let config = config::Config::new()
+            .baudrate(Hertz(self.baud_rate))
+            .stop_bits(match self.stop_bits {
+                StopBits::One => config::StopBits::STOP1,
+                StopBits::OneAndHalf => config::StopBits::STOP1P5,
+                StopBits::Two => config::StopBits::STOP2,
+            });
+
+        match self.parity {
+            Parity::None => config.parity_none(),
+            Parity::Even => config.parity_even(),
+            Parity::Odd => config.parity_odd(),
+        }
+    }
+
+    /// Reconfigures the running driver. The driver itself is split between the uart threads,
+    /// so this goes straight to ESP-IDF.
+    pub fn apply(&self) -> anyhow::Result<()> {
+        let parity: uart_parity_t = match self.parity {
+            Parity::None => uart_parity_t_UART_PARITY_DISABLE,
+            Parity::Even => uart_parity_t_UART_PARITY_EVEN,
+            Parity::Odd => uart_parity_t_UART_PARITY_ODD,
+        };
+
+        let stop_bits: uart_stop_bits_t = match self.stop_bits {
+            StopBits::One => uart_stop_bits_t_UART_STOP_BITS_1,
+            StopBits::OneAndHalf => uart_stop_bits_t_UART_STOP_BITS_1_5,
+            StopBits::Two => uart_stop_bits_t_UART_STOP_BITS_2,
+        };
+
+        esp!(unsafe { uart_set_baudrate(PANEL_UART, self.baud_rate) })?;
+        esp!(unsafe { uart_set_parity(PANEL_UART, parity) })?;
+        esp!(unsafe { uart_set_stop_bits(PANEL_UART, stop_bits) })?;
+
+        Ok(())
+    }
+}
+
+enum ReplySlot {
+    Idle,
+    Waiting,
+    Received(String),
+}
+
+// the first line the panel sends after a raw write, for `uart:send_raw` callers that want it
+static RAW_REPLY: Mutex<ReplySlot> = Mutex::new(ReplySlot::Idle);
 
-use super::wand::Lights;
+/// Starts listening for a reply. Call this before writing, so a fast reply isn't missed.
+pub fn expect_reply() {
+    *RAW_REPLY.lock() = ReplySlot::Waiting;
+}
 
-pub static UART_QUEUE: StaticChannel<Lights, 32, DefaultRecycle> =
-    StaticChannel::<Lights, 32, DefaultRecycle>::new();
+/// The line after the last `expect_reply`, if it came in already. Doesn't block.
+pub fn take_reply() -> Option<String> {
+    let mut slot = RAW_REPLY.lock();
+    match std::mem::replace(&mut *slot, ReplySlot::Idle) {
+        ReplySlot::Received(line) => Some(line),
+        waiting => {
+            *slot = waiting;
+            None
+        }
+    }
+}
+
+/// Stops listening, e.g. once the caller gave up waiting.
+pub fn cancel_reply() {
+    *RAW_REPLY.lock() = ReplySlot::Idle;
+}
+
+fn offer_reply(line: &str) {
+    let mut slot = RAW_REPLY.lock();
+    if matches!(*slot, ReplySlot::Waiting) && !line.trim().is_empty() {
+        *slot = ReplySlot::Received(line.trim().to_owned());
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    // received from the panel
+    Rx,
+    // sent to the panel
+    Tx,
+    // pretending to come from the panel, e.g. through the monitor
+    Injected,
+}
+
+/// A line that went over the panel UART, as seen by the `/uart/monitor` websocket.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct MonitorEntry {
+    // milliseconds since boot
+    pub at_ms: u64,
+    pub direction: Direction,
+    // cut short if it doesn't fit
+    pub line: ArrayString<32>,
+}
+
+impl MonitorEntry {
+    pub fn new(direction: Direction, line: &str) -> Self {
+        let line = line.trim();
+        let mut end = line.len().min(32);
+        while !line.is_char_boundary(end) {
+            end -= 1;
+        }
+
+        MonitorEntry {
+            at_ms: unsafe { esp_timer_get_time() } as u64 / 1000,
+            direction,
+            line: ArrayString::from(&line[..end]).unwrap_or_default(),
+        }
+    }
+
+    /// Pushes the entry onto the monitor queue, dropping it if nobody is keeping up.
+    pub fn record(self, monitor: &Queue<MonitorEntry>) {
+        let _ = monitor.send_back(self, 0);
+    }
+}
 
 pub fn spawn_uart_thread(
     engine: RpcRequester,
     uart: UartDriver<'static>,
-    uart_bus: Arc<Queue<ArrayString<32>>>,
+    monitor: Arc<Queue<MonitorEntry>>,
 ) -> (
     std::thread::JoinHandle<()>,
     std::thread::JoinHandle<()>,
-    StaticSender<Lights>,
+    StaticSender<LightCommand>,
 ) {
     let (uart_tx_channel, uart_rx_channel) = UART_QUEUE.split();
     let (mut uart_tx, uart_rx) = uart.into_split();
     let mut buf = VecDeque::with_capacity(256);
 
+    let tx_monitor = Arc::clone(&monitor);
+
     let receiver_thread = std::thread::spawn(move || {
         loop {
             let mut temp_buf: [u8; 8] = [0; 8];
-            let bytes_read = uart_rx.read(&mut temp_buf, BLOCK).unwrap();
+            let bytes_read = match uart_rx.read(&mut temp_buf, BLOCK) {
+                Ok(n) => n,
+                Err(e) => {
+                    log::error!("Failed to read from the panel: {e}");
+                    continue;
+                }
+            };
             buf.extend(&temp_buf[..bytes_read]);
 
             let rem = buf.make_contiguous();
             let mut cursor = 0;
 
             for pos in memchr_iter(b'\n', rem) {
-                let mut slot = engine.req_tx.send_ref().unwrap();
-                slot.buffer.extend_from_slice(&rem[cursor..pos]);
-                slot.src = MessageSource::Uart;
+                match engine.req_tx.send_ref() {
+                    Ok(mut slot) => {
+                        slot.buffer.extend_from_slice(&rem[cursor..pos]);
+                        slot.src = MessageSource::Uart;
+                    }
+                    Err(_) => Fault::RequestDropped.record(),
+                }
+
                 if let Ok(s) = str::from_utf8(&rem[cursor..pos]) {
-                    let _ = uart_bus.send_back(ArrayString::from_str(s).unwrap(), 0);
+                    MonitorEntry::new(Direction::Rx, s).record(&monitor);
+                    offer_reply(s);
                 }
                 // tx.send(String::from_utf8_lossy(&rem[cursor..pos]).into_owned()).unwrap();
-                cursor = pos;
+                cursor = pos + 1;
             }
 
             buf.drain(..cursor);
+
+            // noise on the line that never ends in a newline shouldn't eat all our memory
+            if buf.len() > MAX_LINE_LEN {
+                log::warn!(
+                    "Dropped {} bytes from the panel without a newline",
+                    buf.len()
+                );
+                Fault::InvalidRequest.record();
+                buf.clear();
+            }
         }
     });
 
     let sender_thread = std::thread::spawn(move || {
         let mut str = String::new();
-        for line in &uart_rx_channel {
-            str.clear();
-            line.write_into(&mut str);
-            uart_tx.write_all(str.as_bytes()).unwrap();
+        let mut animator = Animator::default();
+
+        // raw writes shouldn't be followed by a lights frame
+        let mut skip_frame = false;
+
+        loop {
+            let (lights, next_frame) = animator.frame(Instant::now());
+            if !std::mem::take(&mut skip_frame) {
+                str.clear();
+                lights.write_into(&mut str);
+                if let Err(e) = uart_tx.write_all(str.as_bytes()) {
+                    log::error!("Failed to write lights to the panel: {e}");
+                }
+                MonitorEntry::new(Direction::Tx, &str).record(&tx_monitor);
+            }
+
+            // only wake up on our own while an animation needs its next frame
+            let cmd = match next_frame {
+                Some(wait) => match uart_rx_channel.recv_timeout(wait) {
+                    Ok(cmd) => cmd,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(_) => break,
+                },
+                None => match uart_rx_channel.recv() {
+                    Some(cmd) => cmd,
+                    None => break,
+                },
+            };
+
+            if let LightCommand::Raw(ref bytes) = cmd {
+                if let Err(e) = uart_tx.write_all(bytes) {
+                    log::error!("Failed to write raw bytes to the panel: {e}");
+                }
+                MonitorEntry::new(Direction::Tx, &String::from_utf8_lossy(bytes))
+                    .record(&tx_monitor);
+                skip_frame = true;
+                continue;
+            }
+
+            animator.handle(cmd, Instant::now());
         }
     });
 
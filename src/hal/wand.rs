@@ -1,27 +1,259 @@
-use esp_idf_svc::hal::ledc::LedcDriver;
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use esp_idf_svc::{
+    hal::ledc::LedcDriver,
+    sys::{esp, ledc_mode_t_LEDC_LOW_SPEED_MODE, ledc_set_freq, ledc_timer_t_LEDC_TIMER_0},
+};
 
 use serde::{Deserialize, Serialize};
 use thingbuf::mpsc::blocking::StaticSender;
 
-use crate::{config::ConfigType, impl_conf_type};
+use crate::{config::ConfigType, impl_conf_type, impl_rpc_schema};
 
-#[derive(Serialize, Deserialize)]
-#[repr(transparent)]
+use super::lights::LightCommand;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LightMode {
+    // light up a bar according to the thresholds
+    #[default]
+    Intensity,
+    Off,
+    On,
+    // walk through the lights with the steps of the playing pattern
+    Pattern,
+}
+
+impl_rpc_schema!(LightMode => ["intensity", "off", "on", "pattern"]);
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct LightMappings {
-    thresholds: [i64; 4],
+    pub thresholds: [i64; 4],
+    #[serde(default)]
+    pub mode: LightMode,
 }
 
+impl_rpc_schema!(LightMappings => { thresholds: [i64; 4], mode: LightMode });
+
 impl Default for LightMappings {
     fn default() -> Self {
         LightMappings {
             thresholds: [-1, 25, 50, 75],
+            mode: LightMode::Intensity,
         }
     }
 }
 
 impl_conf_type!(LightMappings, "/littlefs/lights.json", LIGHT_MAPPINGS);
 
-#[derive(Serialize, Deserialize, Clone, Copy, Default)]
+#[derive(Serialize, Deserialize)]
+pub struct RampConfig {
+    // percent per second the output is allowed to move by. 0 jumps straight to the target
+    pub rate: u32,
+}
+
+impl Default for RampConfig {
+    fn default() -> Self {
+        RampConfig { rate: 200 }
+    }
+}
+
+impl_conf_type!(RampConfig, "/littlefs/ramp.json", RAMP_CONFIG);
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IntensityLimits {
+    // lowest level the wand runs at when it's on at all
+    pub floor: i64,
+    // highest level any input is allowed to reach
+    pub ceiling: i64,
+    // changes only apply after a button on the wand itself is pressed
+    #[serde(default)]
+    pub require_confirmation: bool,
+}
+
+impl_rpc_schema!(IntensityLimits => IntensityLimits::default());
+
+impl Default for IntensityLimits {
+    fn default() -> Self {
+        IntensityLimits {
+            floor: 0,
+            ceiling: 100,
+            require_confirmation: false,
+        }
+    }
+}
+
+impl_conf_type!(IntensityLimits, "/littlefs/limits.json", INTENSITY_LIMITS);
+
+impl IntensityLimits {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(0..=100).contains(&self.floor) || !(0..=100).contains(&self.ceiling) {
+            bail!("Floor and ceiling have to be within 0..=100.");
+        }
+
+        if self.floor > self.ceiling {
+            bail!("The floor can't be above the ceiling.");
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&self, percent: i64) -> i64 {
+        if percent <= 0 {
+            0
+        } else {
+            percent.clamp(self.floor.max(1), self.ceiling)
+        }
+    }
+}
+
+const LIMITS_CONFIRMATION_WINDOW: Duration = Duration::from_secs(30);
+
+static PENDING_LIMITS: parking_lot::Mutex<Option<(IntensityLimits, Instant)>> =
+    parking_lot::Mutex::new(None);
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LimitsUpdate {
+    Applied,
+    AwaitingConfirmation,
+}
+
+impl_rpc_schema!(LimitsUpdate => ["applied", "awaiting_confirmation"]);
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseCurve {
+    Linear,
+    Gamma { gamma: f32 },
+    // output levels (0-100) at evenly spaced points from 0% to 100% intensity
+    Table { points: Vec<u8> },
+}
+
+impl_rpc_schema!(ResponseCurve => "type" {
+    "linear",
+    "gamma" { gamma: f32 },
+    "table" { points: Vec<u8> },
+});
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MotorProfile {
+    // PWM frequency in Hz
+    pub frequency: u32,
+    // duty percent for the lowest non-zero intensity; below this the motor usually doesn't spin
+    pub min_duty: f32,
+    // duty percent at 100% intensity
+    pub max_duty: f32,
+    pub curve: ResponseCurve,
+    #[serde(default)]
+    pub kick_start: Option<KickStart>,
+}
+
+impl_rpc_schema!(MotorProfile => {
+    frequency: u32,
+    min_duty: f32,
+    max_duty: f32,
+    curve: ResponseCurve,
+    kick_start: Option<KickStart>,
+});
+
+// briefly overdrives the motor when starting from standstill, so it doesn't stall at low levels
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct KickStart {
+    // duty percent to drive while kicking
+    pub duty: f32,
+    pub duration_ms: u32,
+}
+
+impl_rpc_schema!(KickStart => { duty: f32, duration_ms: u32 });
+
+impl Default for MotorProfile {
+    fn default() -> Self {
+        MotorProfile {
+            frequency: 5000,
+            min_duty: 0.0,
+            max_duty: 100.0,
+            curve: ResponseCurve::Linear,
+            kick_start: None,
+        }
+    }
+}
+
+impl_conf_type!(MotorProfile, "/littlefs/motor.json", MOTOR_PROFILE);
+
+impl MotorProfile {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(500..=40_000).contains(&self.frequency) {
+            bail!("PWM frequency has to be between 500Hz and 40kHz.");
+        }
+
+        if !(0.0..=100.0).contains(&self.min_duty)
+            || !(0.0..=100.0).contains(&self.max_duty)
+            || self.min_duty > self.max_duty
+        {
+            bail!("Duty limits have to satisfy 0 <= min_duty <= max_duty <= 100.");
+        }
+
+        if let Some(kick) = self.kick_start {
+            if !(0.0..=100.0).contains(&kick.duty) {
+                bail!("Kick-start duty has to be within 0..=100.");
+            }
+
+            if kick.duration_ms > 1000 {
+                bail!("Kick-start pulses can be at most 1000ms long.");
+            }
+
+            let ceiling = IntensityLimits::read().ceiling;
+            if kick.duty > self.duty_for(ceiling as f32) {
+                bail!("Kick-start duty can't be above the duty for the {ceiling}% ceiling.");
+            }
+        }
+
+        match &self.curve {
+            ResponseCurve::Linear => {}
+            ResponseCurve::Gamma { gamma } => {
+                if !(0.1..=10.0).contains(gamma) {
+                    bail!("Gamma has to be between 0.1 and 10.");
+                }
+            }
+            ResponseCurve::Table { points } => {
+                if !(2..=32).contains(&points.len()) {
+                    bail!("Lookup tables need between 2 and 32 points.");
+                }
+
+                if points.iter().any(|p| *p > 100) {
+                    bail!("Lookup table points have to be within 0..=100.");
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maps an intensity percent onto the duty percent the motor should be driven at.
+    pub fn duty_for(&self, percent: f32) -> f32 {
+        if percent <= 0.0 {
+            return 0.0;
+        }
+
+        let x = (percent / 100.0).min(1.0);
+        let shaped = match &self.curve {
+            ResponseCurve::Linear => x,
+            ResponseCurve::Gamma { gamma } => x.powf(*gamma),
+            ResponseCurve::Table { points } => {
+                let pos = x * (points.len() - 1) as f32;
+                let idx = (pos as usize).min(points.len() - 2);
+                let (lo, hi) = (points[idx] as f32, points[idx + 1] as f32);
+                (lo + (hi - lo) * (pos - idx as f32)) / 100.0
+            }
+        };
+
+        self.min_duty + shaped * (self.max_duty - self.min_duty)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
 pub struct Lights {
     pub mid_low: bool,
     pub mid_high: bool,
@@ -30,6 +262,25 @@ pub struct Lights {
 }
 
 impl Lights {
+    pub const fn all(on: bool) -> Self {
+        Lights {
+            mid_low: on,
+            mid_high: on,
+            top: on,
+            bottom: on,
+        }
+    }
+
+    /// Only the light at `idx`, counting from the bottom.
+    pub const fn single(idx: usize) -> Self {
+        Lights {
+            mid_low: idx == 1,
+            mid_high: idx == 2,
+            top: idx == 3,
+            bottom: idx == 0,
+        }
+    }
+
     pub fn from_mapping(val: i64, mapping: &[i64; 4]) -> Self {
         Lights {
             mid_low: val > mapping[1],
@@ -52,32 +303,238 @@ impl Lights {
 pub struct Wand {
     pub percent: i64,
     pub driver: LedcDriver<'static>,
-    pub uart_tx: StaticSender<Lights>,
+    pub uart_tx: StaticSender<LightCommand>,
+    actual: f32,
+    last_tick: Instant,
+    limit: i64,
+    fault: bool,
+    kick_until: Option<Instant>,
+    pattern_step: Option<usize>,
 }
 
 impl Wand {
+    pub fn new(driver: LedcDriver<'static>, uart_tx: StaticSender<LightCommand>) -> Self {
+        Wand {
+            percent: 0,
+            driver,
+            uart_tx,
+            actual: 0.0,
+            last_tick: Instant::now(),
+            limit: 100,
+            fault: false,
+            kick_until: None,
+            pattern_step: None,
+        }
+    }
+
+    /// Caps the output without touching the requested level, e.g. for thermal derating.
+    pub fn set_limit(&mut self, limit: i64) {
+        self.limit = limit.clamp(0, 100);
+    }
+
+    /// Forces the motor off and ignores new levels until the fault is cleared.
+    pub fn set_fault(&mut self, fault: bool) {
+        self.fault = fault;
+        if fault {
+            self.percent = 0;
+            self.drive(0.0);
+        } else {
+            self.refresh_lights();
+        }
+    }
+
+    pub fn is_faulted(&self) -> bool {
+        self.fault
+    }
+
+    /// Stores new intensity limits, or parks them until a button press if confirmation is required.
+    pub fn set_intensity_limits(
+        &mut self,
+        limits: IntensityLimits,
+    ) -> anyhow::Result<LimitsUpdate> {
+        limits.validate()?;
+
+        if IntensityLimits::read().require_confirmation {
+            *PENDING_LIMITS.lock() = Some((limits, Instant::now()));
+            return Ok(LimitsUpdate::AwaitingConfirmation);
+        }
+
+        limits.store()?;
+        self.set_percent(self.percent);
+
+        Ok(LimitsUpdate::Applied)
+    }
+
+    /// Applies limits waiting for confirmation. Returns whether there were any, so the press can be consumed.
+    pub fn confirm_pending_limits(&mut self, now: Instant) -> bool {
+        let Some((limits, requested_at)) = PENDING_LIMITS.lock().take() else {
+            return false;
+        };
+
+        if now.duration_since(requested_at) > LIMITS_CONFIRMATION_WINDOW {
+            return false;
+        }
+
+        if let Err(e) = limits.store() {
+            log::error!("Failed to store intensity limits: {e}");
+        }
+
+        log::info!("Intensity limits confirmed");
+        self.set_percent(self.percent);
+
+        true
+    }
+
+    /// Applies a new motor profile, retuning the PWM timer if the frequency changed.
+    pub fn set_profile(&mut self, profile: MotorProfile) -> anyhow::Result<()> {
+        profile.validate()?;
+
+        if profile.frequency != MotorProfile::read().frequency {
+            // the driver owns its timer, so retune it through the underlying ledc api
+            esp!(unsafe {
+                ledc_set_freq(
+                    ledc_mode_t_LEDC_LOW_SPEED_MODE,
+                    ledc_timer_t_LEDC_TIMER_0,
+                    profile.frequency,
+                )
+            })?;
+        }
+
+        profile.store()?;
+        self.drive(self.actual);
+
+        Ok(())
+    }
+
+    fn output_target(&self) -> f32 {
+        if self.fault {
+            0.0
+        } else {
+            self.percent.min(self.limit) as f32
+        }
+    }
+
+    /// The level the wand is moving towards.
     pub fn get_percent(&mut self) -> i64 {
         self.percent
     }
 
+    /// The level the motor is actually being driven at right now.
+    pub fn get_actual(&self) -> i64 {
+        self.actual.round() as i64
+    }
+
     pub fn set_percent(&mut self, percent: i64) {
-        let percent = if percent > 100 {
-            percent
-        } else if percent < 0 {
-            0
+        if self.fault {
+            return;
+        }
+
+        let percent = IntensityLimits::CACHE.with(|v| v.borrow_mut().load().apply(percent));
+
+        self.percent = percent;
+
+        if RampConfig::CACHE.with(|v| v.borrow_mut().load().rate) == 0 {
+            self.drive(self.output_target());
+        }
+
+        self.refresh_lights();
+    }
+
+    /// Tells the wand which step of a pattern is playing, for the pattern light mode.
+    pub fn set_pattern_step(&mut self, step: Option<usize>) {
+        if self.pattern_step == step {
+            return;
+        }
+
+        self.pattern_step = step;
+        if LightMappings::CACHE.with(|v| v.borrow_mut().load().mode) == LightMode::Pattern {
+            self.refresh_lights();
+        }
+    }
+
+    pub fn refresh_lights(&self) {
+        let lights = LightMappings::CACHE.with(|val| {
+            let mut binding = val.borrow_mut();
+            let mappings = binding.load();
+            match (mappings.mode, self.pattern_step) {
+                (LightMode::Off, _) => Lights::all(false),
+                (LightMode::On, _) => Lights::all(true),
+                (LightMode::Pattern, Some(step)) => Lights::single(step % 4),
+                _ => Lights::from_mapping(self.percent, &mappings.thresholds),
+            }
+        });
+
+        let _ = self.uart_tx.send(LightCommand::Set(lights));
+    }
+
+    /// Turns the motor off right away, skipping the ramp.
+    pub fn stop(&mut self) {
+        self.set_percent(0);
+        self.drive(0.0);
+    }
+
+    /// Glides the output towards the target, at most by the configured ramp rate.
+    pub fn tick(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_tick).as_secs_f32();
+        self.last_tick = now;
+
+        if self.kick_until.is_some_and(|until| now >= until) {
+            self.kick_until = None;
+            self.drive(self.actual);
+        }
+
+        let target = self.output_target();
+        if self.actual == target {
+            return;
+        }
+
+        let rate = RampConfig::CACHE.with(|v| v.borrow_mut().load().rate);
+        let next = if rate == 0 {
+            target
         } else {
-            percent
+            let step = rate as f32 * elapsed;
+            if self.actual < target {
+                (self.actual + step).min(target)
+            } else {
+                (self.actual - step).max(target)
+            }
         };
 
-        let max_duty = self.driver.get_max_duty();
-        self.driver
-            .set_duty(percent as u32 * max_duty / 100)
-            .unwrap();
-        self.percent = percent;
+        self.drive(next);
+    }
 
-        let lights = LightMappings::CACHE
-            .with(|val| Lights::from_mapping(percent, &val.borrow_mut().load().thresholds));
+    fn drive(&mut self, percent: f32) {
+        // the kick can't go past what the ceiling or thermal derating allow
+        let ceiling = IntensityLimits::CACHE.with(|v| v.borrow_mut().load().ceiling);
+        let (mut duty, kick_start, kick_cap) = MotorProfile::CACHE.with(|v| {
+            let mut binding = v.borrow_mut();
+            let profile = binding.load();
+            (
+                profile.duty_for(percent),
+                profile.kick_start,
+                profile.duty_for(self.limit.min(ceiling) as f32),
+            )
+        });
+
+        if percent <= 0.0 {
+            self.kick_until = None;
+        } else if self.actual <= 0.0 {
+            // leaving standstill
+            self.kick_until = kick_start
+                .map(|kick| Instant::now() + Duration::from_millis(kick.duration_ms as u64));
+        }
 
-        let _ = self.uart_tx.send(lights);
+        if let (Some(kick), Some(_)) = (kick_start, self.kick_until) {
+            duty = duty.max(kick.duty.min(kick_cap));
+        }
+
+        let max_duty = self.driver.get_max_duty();
+        if let Err(e) = self
+            .driver
+            .set_duty((duty * max_duty as f32 / 100.0) as u32)
+        {
+            log::error!("Failed to set the motor duty: {e}");
+        }
+        self.actual = percent;
     }
 }
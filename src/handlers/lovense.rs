@@ -1,19 +1,19 @@
-use std::{ops::Range, rc::Rc};
+use std::{rc::Rc, time::Instant};
 
 use serde::{Deserialize, Serialize};
 use thingbuf::mpsc::blocking::SendRef;
 
 use crate::{
     config::ConfigType,
+    control::ControlArbiter,
+    diagnostics::Fault,
     hal::wand::Wand,
     impl_conf_type,
-    rpc::{ResponseMessage, ResponseTag},
+    lovense::{self, LovenseCommand},
+    pattern::PatternPlayer,
+    rpc::{MessageSource, ResponseMessage, ResponseTag},
 };
 
-fn map_range(lhs: Range<i64>, rhs: Range<i64>, val: i64) -> i64 {
-    rhs.start + ((val - lhs.start) * (rhs.end - rhs.start) / (lhs.end - lhs.start))
-}
-
 #[derive(Serialize, Deserialize, Default)]
 pub struct LovenseConfig {
     pub start: i64,
@@ -24,62 +24,51 @@ impl_conf_type!(LovenseConfig, "/littlefs/lovense.json", LOVENSE_CONFIG);
 
 pub struct LovenseHandler {
     pub pwm: Rc<parking_lot::Mutex<Wand>>,
+    pub patterns: Rc<parking_lot::Mutex<PatternPlayer>>,
+    pub control: Rc<parking_lot::Mutex<ControlArbiter>>,
 }
 
 impl LovenseHandler {
-    pub fn handle(&mut self, args: &str, mut send_slot: SendRef<'_, ResponseMessage>) {
-        let Some(msg_end) = args.find(';') else {
-            send_slot.tag = ResponseTag::Discard;
-            return;
-        };
-
-        let args: Vec<&str> = args[..msg_end].split_terminator(':').collect();
-
-        log::info!(target: "lovense", "Lovense Command: {args:?}");
-
-        let res: &[&str] = match args[0] {
-            "Battery" => &["100"],
-            "Status" => &["2"],
-            "GetLight" => &["Light", "1"],
-            "Vibrate" => {
-                let lovense_range = 0..20;
-                let target_range = LovenseConfig::CACHE.with(|v| {
-                    let mut binding = v.borrow_mut();
-                    let val = binding.load();
-                    val.start..val.end
-                });
-
-                let strength = args[1].parse::<i64>().unwrap();
-                let mapped = if strength > 0 {
-                    map_range(lovense_range, target_range, strength)
-                } else {
-                    0
-                };
-
-                self.pwm.lock().set_percent(mapped);
+    pub fn handle(&mut self, msg: &[u8], mut send_slot: SendRef<'_, ResponseMessage>) {
+        // for lovense messages, we add a last byte to the array indicating it's a lovense one
+        send_slot.tag = ResponseTag::Lovense;
 
-                // let lights = Lights::from_mapping(mapped, &self.mappings.light_mappings);
+        let command = match LovenseCommand::parse(msg) {
+            Ok(command) => command,
+            Err(e) => {
+                log::warn!(target: "lovense", "Invalid Lovense command: {e}");
+                Fault::InvalidRequest.record();
+                send_slot.buffer.extend_from_slice(b"ERR");
+                return;
+            }
+        };
 
-                // let mut slot = self.uart_tx.send_ref().unwrap();
-                // slot.clear();
-                // lights.write_into(&mut slot);
-                // drop(slot);
-                // let max_duty = 999;
-                // let duty = max_duty as f64 * (mapped as f64 / 100.0);
-                // let duty: i64 = duty.trunc() as i64;
+        log::info!(target: "lovense", "Lovense Command: {command:?}");
+
+        if let LovenseCommand::Vibrate(strength) = command {
+            let target_range = LovenseConfig::CACHE.with(|v| {
+                let mut binding = v.borrow_mut();
+                let val = binding.load();
+                val.start..val.end
+            });
+
+            if let Err(e) = self
+                .control
+                .lock()
+                .check(MessageSource::BleLovense, Instant::now())
+            {
+                log::info!(target: "lovense", "Ignoring Vibrate: {e}");
+                send_slot.tag = ResponseTag::Discard;
+                return;
+            }
 
-                // if mapped > 0 {
-                //     self.uart_tx.send("1111,".to_string()).unwrap();
-                // } else {
-                //     self.uart_tx.send("1111,".to_string()).unwrap();
-                // }
+            let mapped = lovense::map_strength(strength, target_range);
 
-                &[]
-            }
-            _ => &[],
-        };
+            self.patterns.lock().stop();
+            self.pwm.lock().set_percent(mapped);
+        }
 
-        let mut res_iter = res.iter();
+        let mut res_iter = command.reply().iter();
 
         if let Some(first) = res_iter.next() {
             send_slot.buffer.extend_from_slice(first.as_bytes());
@@ -89,10 +78,5 @@ impl LovenseHandler {
             send_slot.buffer.push(b':');
             send_slot.buffer.extend_from_slice(arg.as_bytes());
         }
-
-        // for lovense messages, we add a last byte to the array indicating it's a lovense one
-        send_slot.tag = ResponseTag::Lovense;
-
-        drop(send_slot);
     }
 }
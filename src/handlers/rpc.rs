@@ -1,21 +1,41 @@
-use std::{net::Ipv4Addr, rc::Rc};
-
-use anyhow::anyhow;
-use esp_idf_hal::{
-    sys::{esp, esp_get_free_heap_size},
-    temp_sensor::TempSensorDriver,
+use std::{
+    net::Ipv4Addr,
+    rc::Rc,
+    time::{Duration, Instant},
 };
+
+use anyhow::{anyhow, bail};
+use esp_idf_hal::sys::{esp, esp_get_free_heap_size};
 use esp_idf_svc::sys::esp_mac_type_t;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use thingbuf::mpsc::blocking::StaticSender;
 
 #[cfg(feature = "usb_pd")]
 use crate::hal::husb238::{Capabilities, Husb238Driver, Status};
 
 use crate::{
+    buttons::{ButtonAction, ButtonMappings},
     config::ConfigType,
-    hal::wand::{Lights, Wand},
-    rpc::{MessageRecycler, MessageSource, RequestMessage, RpcCall, RpcResponse},
+    control::{ControlArbiter, ControlConfig, ControlStatus},
+    diagnostics::{Diagnostics, Fault},
+    events::{EventBus, Topic, TopicSet},
+    hal::{
+        lights::{LightCommand, RawFrame},
+        panel::{PanelMonitor, PanelStats, UnknownMessage},
+        uart::{self, UartBusConfig},
+        wand::{
+            IntensityLimits, LightMappings, LightMode, Lights, LimitsUpdate, MotorProfile,
+            RampConfig, Wand,
+        },
+    },
+    impl_rpc_schema,
+    pattern::{self, Pattern, PatternPlayer},
+    registry::{Deferred, MethodIndex, Methods, PendingCall, RpcNamespace, RpcRegistry},
+    rpc::{Encoding, MessageRecycler, MessageSource, ReplyRoute, RequestMessage, RpcResponse},
+    schema::{param, RpcSchema},
+    session::{SessionConfig, SessionGuard, SessionReport},
+    stats::{StatsTracker, UsageStats},
+    thermal::{ThermalConfig, ThermalReport, ThermalSupervisor},
     wifi::{WifiConfig, WifiManager},
     BuildInfo, BUILD_INFO, LAST_UART_MSG,
 };
@@ -27,73 +47,123 @@ use esp_idf_hal::sys::{
 use super::lovense::LovenseConfig;
 
 pub struct RpcHandler {
-    sys: SysHandler,
-    conn: ConnHandler,
-    wand: WandHandler,
-    uart: UartHandler,
+    registry: RpcRegistry,
+    raw_wait: Rc<parking_lot::Mutex<Option<RawWait>>>,
 }
 
 impl RpcHandler {
     pub fn new(
         pwm: Rc<parking_lot::Mutex<Wand>>,
-        temp: TempSensorDriver<'static>,
+        patterns: Rc<parking_lot::Mutex<PatternPlayer>>,
+        control: Rc<parking_lot::Mutex<ControlArbiter>>,
+        thermal: Rc<parking_lot::Mutex<ThermalSupervisor>>,
+        session: Rc<parking_lot::Mutex<SessionGuard>>,
+        stats: Rc<parking_lot::Mutex<StatsTracker>>,
+        panel: Rc<parking_lot::Mutex<PanelMonitor>>,
+        events: Rc<parking_lot::Mutex<EventBus>>,
         wifi: WifiManager,
-        uart_tx: StaticSender<Lights>,
-        req_tx: StaticSender<RequestMessage, MessageRecycler>
+        uart_tx: StaticSender<LightCommand>,
+        req_tx: StaticSender<RequestMessage, MessageRecycler>,
     ) -> Self {
-        Self {
-            sys: SysHandler { temp_sensor: temp, req_tx },
-            conn: ConnHandler { wifi },
-            wand: WandHandler { pwm },
-            uart: UartHandler { uart_tx },
-        }
+        let mut registry = RpcRegistry::default();
+        let methods = registry.index();
+        let raw_wait = Rc::default();
+
+        registry
+            .register(SysHandler {
+                thermal,
+                session,
+                stats,
+                pwm: Rc::clone(&pwm),
+                req_tx,
+                events,
+                methods,
+                caller: ReplyRoute {
+                    src: MessageSource::HttpRpc,
+                    encoding: Encoding::Json,
+                    correlation: 0,
+                    batch: false,
+                },
+            })
+            .register(ConnHandler { wifi })
+            .register(WandHandler {
+                pwm,
+                patterns,
+                control,
+                caller: MessageSource::HttpRpc,
+            })
+            .register(UartHandler {
+                uart_tx,
+                panel,
+                raw_wait: Rc::clone(&raw_wait),
+                in_batch: false,
+            });
+
+        Self { registry, raw_wait }
     }
 
-    pub fn rpc_call(&mut self, call: RpcCall<'_>, response: &mut Vec<u8>) -> anyhow::Result<()> {
-        let (namespace, method) = call.method.split_once(':').unwrap();
-        let res = match namespace {
-            "sys" => self.sys.handle(call, method),
-            "conn" => self.conn.handle(call, method),
-            "wand" => self.wand.handle(call, method),
-            "uart" => self.uart.handle(call, method),
-            _ => RpcResponse::new::<(), _>(
-                call.id,
-                anyhow::Result::Err(anyhow!("Invalid namespace.")),
-            ),
-        };
-
-        serde_json::to_writer(response, &res)?;
-        Ok(())
+    /// Adds a namespace on top of the built-in ones, e.g. for optional hardware.
+    pub fn register<N: RpcNamespace>(&mut self, namespace: N) {
+        self.registry.register(namespace);
     }
-}
 
-macro_rules! handle_methods {
-    ($self:ident, $call_method:expr, $call:expr => withargs [$($method:ident);*] noargs [$($noargsmethod:ident);*]) => {
-        {
-            match $call_method {
-                $(
-                    // todo: don't unwrap here
-                    stringify!($method) => {
-                        let params = match serde_json::from_str($call.params.get()) {
-                            Ok(v) => v,
-                            Err(e) => return RpcResponse::new::<(), anyhow::Error>($call.id, anyhow::Result::Err(anyhow!("Invalid JSON for the arguments: {}", e))),
-                        };
-
-                        RpcResponse::new::<_, anyhow::Error>($call.id, $self.$method(params))
-                    },
-                )*
-                $(
-                    stringify!($noargsmethod) => RpcResponse::new::<_, anyhow::Error>($call.id, $self.$noargsmethod()),
-                )*
-                _ => RpcResponse::new::<(), anyhow::Error>($call.id, anyhow::Result::Err(anyhow!("Invalid method.")))
+    /// Handles a raw request, answering in the same encoding it came in. See
+    /// [`RpcRegistry::handle`]. Returns whether some of it gets answered later through `poll`.
+    pub fn handle_message(
+        &mut self,
+        buf: &[u8],
+        route: ReplyRoute,
+        response: &mut Vec<u8>,
+    ) -> anyhow::Result<bool> {
+        let res = self.registry.handle(buf, route, response);
+
+        // only `uart:send_raw` defers, and only one of those can be waiting at a time
+        let mut answered_later = false;
+        for call in self.registry.take_deferred() {
+            if let Some(wait) = self.raw_wait.lock().as_mut() {
+                wait.reply_to = Some((call, route));
             }
+            answered_later = true;
         }
+
+        res.map(|()| answered_later)
+    }
+
+    /// The answer to a call that was left waiting, once it's ready. Called on every tick.
+    pub fn poll(&mut self, now: Instant) -> Option<(ReplyRoute, RpcResponse)> {
+        let mut slot = self.raw_wait.lock();
+        let wait = slot.as_ref()?;
+        let res = match uart::take_reply() {
+            Some(reply) => Ok(RawReply {
+                sent: wait.sent,
+                reply: Some(reply),
+            }),
+            None if now >= wait.deadline => {
+                uart::cancel_reply();
+                Err(anyhow!(
+                    "No reply from the panel within {}ms.",
+                    wait.timeout_ms
+                ))
+            }
+            None => return None,
+        };
+
+        // nobody to answer if it was a notification
+        let (call, route) = slot.take()?.reply_to?;
+        Some((route, call.answer(res)))
     }
 }
 
 pub struct SysHandler {
-    temp_sensor: TempSensorDriver<'static>,
-    req_tx: StaticSender<RequestMessage, MessageRecycler>
+    thermal: Rc<parking_lot::Mutex<ThermalSupervisor>>,
+    session: Rc<parking_lot::Mutex<SessionGuard>>,
+    stats: Rc<parking_lot::Mutex<StatsTracker>>,
+    pwm: Rc<parking_lot::Mutex<Wand>>,
+    req_tx: StaticSender<RequestMessage, MessageRecycler>,
+    events: Rc<parking_lot::Mutex<EventBus>>,
+    methods: MethodIndex,
+    // where the call currently being handled came from
+    caller: ReplyRoute,
 }
 
 #[derive(Serialize)]
@@ -102,33 +172,152 @@ pub struct SystemInfo {
     free_memory: u32,
 }
 
-impl SysHandler {
-    pub fn handle(&mut self, call: RpcCall<'_>, method: &str) -> RpcResponse {
-        handle_methods! (self, method, call => withargs [fake_uart] noargs [health; restart; build_info])
+impl_rpc_schema!(SystemInfo => { temperature: f32, free_memory: u32 });
+
+impl RpcNamespace for SysHandler {
+    const NAME: &'static str = "sys";
+
+    fn register(methods: &mut Methods<Self>) {
+        methods
+            .method("fake_uart", Self::fake_uart)
+            .method("set_thermal_config", Self::set_thermal_config)
+            .method("set_session_limits", Self::set_session_limits)
+            .method("subscribe", Self::subscribe)
+            .noargs("health", Self::health)
+            .noargs("restart", Self::restart)
+            .noargs("build_info", Self::build_info)
+            .noargs("describe", Self::describe)
+            .noargs("thermal", Self::thermal)
+            .noargs("reset_thermal", Self::reset_thermal)
+            .noargs("session", Self::session)
+            .noargs("stats", Self::stats)
+            .noargs("reset_stats", Self::reset_stats)
+            .noargs("diagnostics", Self::diagnostics)
+            .noargs("reset_diagnostics", Self::reset_diagnostics)
+            .noargs("subscriptions", Self::subscriptions)
+            .noargs("unsubscribe", Self::unsubscribe);
     }
 
+    fn on_call(&mut self, caller: ReplyRoute) {
+        self.caller = caller;
+    }
+}
+
+impl SysHandler {
     pub fn build_info(&mut self) -> anyhow::Result<BuildInfo> {
         Ok(BUILD_INFO)
     }
 
+    /// An OpenRPC document listing every method we answer to.
+    pub fn describe(&mut self) -> anyhow::Result<serde_json::Value> {
+        Ok(serde_json::json!({
+            "openrpc": "1.2.6",
+            "info": {
+                "title": BUILD_INFO.crate_name,
+                "version": BUILD_INFO.crate_version,
+            },
+            "methods": *self.methods.lock(),
+        }))
+    }
+
     pub fn health(&mut self) -> anyhow::Result<SystemInfo> {
         Ok(SystemInfo {
-            temperature: self.temp_sensor.get_celsius()?,
+            temperature: self.thermal.lock().read_sensor()?,
             free_memory: unsafe { esp_get_free_heap_size() },
         })
     }
 
     pub fn restart(&mut self) -> anyhow::Result<()> {
+        if let Err(e) = self.stats.lock().flush() {
+            log::error!("Failed to persist usage stats before restarting: {e}");
+        }
+
         esp_idf_svc::hal::reset::restart()
     }
 
+    pub fn stats(&mut self) -> anyhow::Result<UsageStats> {
+        Ok(self.stats.lock().stats())
+    }
+
+    pub fn reset_stats(&mut self) -> anyhow::Result<()> {
+        self.stats.lock().reset()
+    }
+
+    /// How often handling input went wrong since the last boot or reset.
+    pub fn diagnostics(&mut self) -> anyhow::Result<Diagnostics> {
+        Ok(Diagnostics::read())
+    }
+
+    pub fn reset_diagnostics(&mut self) -> anyhow::Result<()> {
+        Diagnostics::reset();
+        Ok(())
+    }
+
+    pub fn thermal(&mut self) -> anyhow::Result<ThermalReport> {
+        Ok(self.thermal.lock().report())
+    }
+
+    pub fn reset_thermal(&mut self) -> anyhow::Result<()> {
+        self.thermal.lock().reset(&mut self.pwm.lock())
+    }
+
+    pub fn set_thermal_config(&mut self, args: [ThermalConfig; 1]) -> anyhow::Result<()> {
+        let [conf] = args;
+        if conf.soft_limit >= conf.hard_limit {
+            return Err(anyhow!("The soft limit has to be below the hard limit."));
+        }
+
+        if !(0..=100).contains(&conf.min_percent) {
+            return Err(anyhow!("min_percent has to be within 0..=100."));
+        }
+
+        conf.store()?;
+        Ok(())
+    }
+
+    pub fn session(&mut self) -> anyhow::Result<SessionReport> {
+        Ok(self.session.lock().report(Instant::now()))
+    }
+
+    pub fn set_session_limits(&mut self, args: [SessionConfig; 1]) -> anyhow::Result<()> {
+        let [conf] = args;
+        conf.store()?;
+
+        Ok(())
+    }
+
+    /// Sets which topics get pushed to the calling BLE connection or `/events` websocket as
+    /// `sys:event` notifications.
+    pub fn subscribe(&mut self, args: [Vec<Topic>; 1]) -> anyhow::Result<Vec<Topic>> {
+        let [topics] = args;
+        let topics: TopicSet = topics.into_iter().collect();
+        self.events.lock().subscribe(self.caller, topics)?;
+
+        Ok(topics.topics())
+    }
+
+    pub fn subscriptions(&mut self) -> anyhow::Result<Vec<Topic>> {
+        Ok(self.events.lock().subscriptions(self.caller).topics())
+    }
+
+    pub fn unsubscribe(&mut self) -> anyhow::Result<()> {
+        self.events
+            .lock()
+            .subscribe(self.caller, TopicSet::default())
+    }
+
     pub fn fake_uart(&mut self, args: [String; 1]) -> anyhow::Result<()> {
         let [s] = args;
 
-        let mut slot = self.req_tx.send_ref().unwrap();
+        // we're called from the loop draining the queue, so waiting on it would never end
+        let Ok(mut slot) = self.req_tx.try_send_ref() else {
+            Fault::RequestDropped.record();
+            bail!("The request queue is full.");
+        };
         slot.buffer.append(&mut s.into_bytes());
         slot.buffer.extend_from_slice(b"\r\n");
-        slot.src = MessageSource::Uart;
+        // a fake press mustn't confirm limits or outrank a lease like a real one
+        slot.src = MessageSource::Injected;
         Ok(())
     }
 }
@@ -139,6 +328,8 @@ pub struct Addresses {
     mac: MACAddresses,
 }
 
+impl_rpc_schema!(Addresses => { ip: Ipv4Addr, mac: MACAddresses });
+
 #[derive(Serialize)]
 pub struct MACAddresses {
     mac_base: String,
@@ -146,6 +337,8 @@ pub struct MACAddresses {
     mac_wifi: String,
 }
 
+impl_rpc_schema!(MACAddresses => { mac_base: String, mac_ble: String, mac_wifi: String });
+
 impl MACAddresses {
     fn get_mode(mode: esp_mac_type_t) -> anyhow::Result<String> {
         let mut mac = [0u8; 6];
@@ -169,11 +362,17 @@ pub struct ConnHandler {
     wifi: WifiManager,
 }
 
-impl ConnHandler {
-    pub fn handle(&mut self, call: RpcCall<'_>, method: &str) -> RpcResponse {
-        handle_methods! (self, method, call => withargs [set_wifi] noargs [addr])
+impl RpcNamespace for ConnHandler {
+    const NAME: &'static str = "conn";
+
+    fn register(methods: &mut Methods<Self>) {
+        methods
+            .method("set_wifi", Self::set_wifi)
+            .noargs("addr", Self::addr);
     }
+}
 
+impl ConnHandler {
     pub fn set_wifi(&mut self, args: [WifiConfig; 1]) -> anyhow::Result<()> {
         let [conf] = args;
         let conf = conf.store()?;
@@ -194,23 +393,234 @@ impl ConnHandler {
 
 pub struct WandHandler {
     pub pwm: Rc<parking_lot::Mutex<Wand>>,
+    pub patterns: Rc<parking_lot::Mutex<PatternPlayer>>,
+    pub control: Rc<parking_lot::Mutex<ControlArbiter>>,
+    // source of the call currently being handled
+    caller: MessageSource,
 }
 
-impl WandHandler {
-    pub fn handle(&mut self, call: RpcCall<'_>, method: &str) -> RpcResponse {
-        handle_methods! (self, method, call => withargs [set_percent; update_lovense_mapping] noargs [get_percent])
+#[derive(Serialize)]
+pub struct WandLevel {
+    target: i64,
+    actual: i64,
+}
+
+impl_rpc_schema!(WandLevel => { target: i64, actual: i64 });
+
+#[derive(Serialize)]
+pub struct PatternList {
+    patterns: Vec<String>,
+    playing: Option<String>,
+}
+
+impl_rpc_schema!(PatternList => { patterns: Vec<String>, playing: Option<String> });
+
+impl RpcNamespace for WandHandler {
+    const NAME: &'static str = "wand";
+
+    fn register(methods: &mut Methods<Self>) {
+        methods
+            .method("set_percent", Self::set_percent)
+            .method("update_lovense_mapping", Self::update_lovense_mapping)
+            .method("set_ramp_rate", Self::set_ramp_rate)
+            .method("set_motor_profile", Self::set_motor_profile)
+            .method("set_limits", Self::set_limits)
+            .method("set_button_mappings", Self::set_button_mappings)
+            .method("set_button_increments", Self::set_button_increments)
+            .method("set_light_mappings", Self::set_light_mappings)
+            .method("set_light_mode", Self::set_light_mode)
+            .method("acquire_control", Self::acquire_control)
+            .method("set_control_priorities", Self::set_control_priorities)
+            .method("play_pattern", Self::play_pattern)
+            .method("upload_pattern", Self::upload_pattern)
+            .method("get_pattern", Self::get_pattern)
+            .method("rename_pattern", Self::rename_pattern)
+            .method("delete_pattern", Self::delete_pattern)
+            .noargs("get_percent", Self::get_percent)
+            .noargs("get_ramp_rate", Self::get_ramp_rate)
+            .noargs("get_motor_profile", Self::get_motor_profile)
+            .noargs("get_limits", Self::get_limits)
+            .noargs("get_button_mappings", Self::get_button_mappings)
+            .noargs("get_light_mappings", Self::get_light_mappings)
+            .noargs("release_control", Self::release_control)
+            .noargs("control_owner", Self::control_owner)
+            .noargs("stop_pattern", Self::stop_pattern)
+            .noargs("list_patterns", Self::list_patterns);
+    }
+
+    fn on_call(&mut self, caller: ReplyRoute) {
+        self.caller = caller.src;
     }
+}
 
-    pub fn get_percent(&mut self) -> anyhow::Result<i64> {
-        Ok(self.pwm.lock().get_percent())
+impl WandHandler {
+    pub fn get_percent(&mut self) -> anyhow::Result<WandLevel> {
+        let mut wand = self.pwm.lock();
+        Ok(WandLevel {
+            target: wand.get_percent(),
+            actual: wand.get_actual(),
+        })
     }
 
     pub fn set_percent(&mut self, args: [i64; 1]) -> anyhow::Result<()> {
-        self.pwm.lock().set_percent(args[0]);
+        let mut wand = self.pwm.lock();
+        if wand.is_faulted() {
+            return Err(anyhow!("Motor is shut down due to overheating, see sys:thermal."));
+        }
+
+        self.control.lock().check(self.caller, Instant::now())?;
+
+        // manual control takes over from whatever pattern was running
+        self.patterns.lock().stop();
+        wand.set_percent(args[0]);
+
+        Ok(())
+    }
+
+    pub fn acquire_control(&mut self, args: [u32; 1]) -> anyhow::Result<()> {
+        self.control
+            .lock()
+            .acquire(self.caller, args[0], Instant::now())
+    }
+
+    pub fn release_control(&mut self) -> anyhow::Result<()> {
+        self.control.lock().release(self.caller, Instant::now())
+    }
+
+    pub fn control_owner(&mut self) -> anyhow::Result<ControlStatus> {
+        Ok(self.control.lock().status(Instant::now()))
+    }
+
+    pub fn set_control_priorities(&mut self, args: [ControlConfig; 1]) -> anyhow::Result<()> {
+        let [conf] = args;
+        self.control.lock().check(self.caller, Instant::now())?;
+        conf.validate()?;
+        conf.store()?;
+
+        Ok(())
+    }
+
+    pub fn get_ramp_rate(&mut self) -> anyhow::Result<u32> {
+        Ok(RampConfig::read().rate)
+    }
+
+    pub fn set_ramp_rate(&mut self, args: [u32; 1]) -> anyhow::Result<()> {
+        RampConfig { rate: args[0] }.store()?;
+
+        Ok(())
+    }
+
+    pub fn get_motor_profile(&mut self) -> anyhow::Result<MotorProfile> {
+        Ok((**MotorProfile::read()).clone())
+    }
+
+    pub fn set_motor_profile(&mut self, args: [MotorProfile; 1]) -> anyhow::Result<()> {
+        let [profile] = args;
+        self.pwm.lock().set_profile(profile)
+    }
+
+    pub fn get_limits(&mut self) -> anyhow::Result<IntensityLimits> {
+        Ok((**IntensityLimits::read()).clone())
+    }
+
+    pub fn set_limits(&mut self, args: [IntensityLimits; 1]) -> anyhow::Result<LimitsUpdate> {
+        let [limits] = args;
+        self.pwm.lock().set_intensity_limits(limits)
+    }
+
+    pub fn get_button_mappings(&mut self) -> anyhow::Result<ButtonMappings> {
+        Ok((**ButtonMappings::read()).clone())
+    }
+
+    pub fn set_button_mappings(&mut self, args: [ButtonMappings; 1]) -> anyhow::Result<()> {
+        let [mappings] = args;
+        mappings.store()?;
+
+        Ok(())
+    }
+
+    /// Shorthand for binding the first two buttons to stepping by the given amounts.
+    pub fn set_button_increments(&mut self, args: [i64; 2]) -> anyhow::Result<()> {
+        let mut mappings = (**ButtonMappings::read()).clone();
+        mappings.buttons[0] = ButtonAction::Step { delta: args[0] };
+        mappings.buttons[1] = ButtonAction::Step { delta: args[1] };
+        mappings.store()?;
+
+        Ok(())
+    }
+
+    pub fn get_light_mappings(&mut self) -> anyhow::Result<LightMappings> {
+        Ok((**LightMappings::read()).clone())
+    }
+
+    /// Thresholds for the bottom, mid-low, mid-high and top lights.
+    pub fn set_light_mappings(&mut self, args: [i64; 4]) -> anyhow::Result<()> {
+        LightMappings {
+            thresholds: args,
+            mode: LightMappings::read().mode,
+        }
+        .store()?;
+        self.pwm.lock().refresh_lights();
+
+        Ok(())
+    }
+
+    pub fn set_light_mode(&mut self, args: [LightMode; 1]) -> anyhow::Result<()> {
+        LightMappings {
+            thresholds: LightMappings::read().thresholds,
+            mode: args[0],
+        }
+        .store()?;
+        self.pwm.lock().refresh_lights();
 
         Ok(())
     }
 
+    pub fn play_pattern(&mut self, args: [String; 1]) -> anyhow::Result<()> {
+        let [name] = args;
+        self.control.lock().check(self.caller, Instant::now())?;
+
+        let pattern = pattern::load(&name)?;
+        self.patterns.lock().play(name, pattern, Instant::now());
+
+        Ok(())
+    }
+
+    pub fn upload_pattern(&mut self, args: (String, Pattern)) -> anyhow::Result<()> {
+        let (name, pattern) = args;
+        pattern::store::save(&name, &pattern)
+    }
+
+    pub fn get_pattern(&mut self, args: [String; 1]) -> anyhow::Result<Pattern> {
+        pattern::load(&args[0])
+    }
+
+    pub fn rename_pattern(&mut self, args: [String; 2]) -> anyhow::Result<()> {
+        let [from, to] = args;
+        pattern::store::rename(&from, &to)
+    }
+
+    pub fn delete_pattern(&mut self, args: [String; 1]) -> anyhow::Result<()> {
+        pattern::store::delete(&args[0])
+    }
+
+    pub fn stop_pattern(&mut self) -> anyhow::Result<()> {
+        self.control.lock().check(self.caller, Instant::now())?;
+
+        if self.patterns.lock().stop() {
+            self.pwm.lock().set_percent(0);
+        }
+
+        Ok(())
+    }
+
+    pub fn list_patterns(&mut self) -> anyhow::Result<PatternList> {
+        Ok(PatternList {
+            patterns: pattern::names()?,
+            playing: self.patterns.lock().current().map(str::to_owned),
+        })
+    }
+
     pub fn update_lovense_mapping(&mut self, args: [i64; 2]) -> anyhow::Result<()> {
         LovenseConfig {
             start: args[0],
@@ -222,28 +632,209 @@ impl WandHandler {
     }
 }
 
+// how long `uart:send_raw` may wait for the panel to reply
+const MAX_RAW_REPLY_WAIT_MS: u32 = 2000;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum RawData {
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+/// `[data, timeout_ms?]`, only waits for a reply if a timeout is given.
+#[derive(Deserialize)]
+pub struct SendRawArgs(RawData, #[serde(default)] Option<u32>);
+
+#[derive(Serialize)]
+pub struct RawReply {
+    sent: usize,
+    reply: Option<String>,
+}
+
+impl_rpc_schema!(RawReply => { sent: usize, reply: Option<String> });
+
+// a `uart:send_raw` waiting on the panel's reply, answered from `RpcHandler::poll`
+struct RawWait {
+    sent: usize,
+    timeout_ms: u32,
+    deadline: Instant,
+    reply_to: Option<(PendingCall, ReplyRoute)>,
+}
+
+impl RpcSchema for SendRawArgs {
+    fn schema() -> serde_json::Value {
+        serde_json::json!({ "type": "array" })
+    }
+
+    fn params() -> Vec<serde_json::Value> {
+        let data = serde_json::json!({
+            "anyOf": [
+                { "type": "string" },
+                { "type": "array", "items": { "type": "integer" } },
+            ],
+        });
+
+        vec![param(0, data, true), param(1, <Option<u32>>::schema(), false)]
+    }
+}
+
 pub struct UartHandler {
-    pub uart_tx: StaticSender<Lights>,
+    pub uart_tx: StaticSender<LightCommand>,
+    pub panel: Rc<parking_lot::Mutex<PanelMonitor>>,
+    raw_wait: Rc<parking_lot::Mutex<Option<RawWait>>>,
+    in_batch: bool,
 }
 
-impl UartHandler {
-    pub fn handle(&mut self, call: RpcCall<'_>, method: &str) -> RpcResponse {
-        handle_methods! (self, method, call => withargs [send] noargs [get_last])
+impl RpcNamespace for UartHandler {
+    const NAME: &'static str = "uart";
+
+    fn register(methods: &mut Methods<Self>) {
+        methods
+            .method("send", Self::send)
+            .method("send_raw", Self::send_raw)
+            .method("configure", Self::configure)
+            .noargs("get_last", Self::get_last)
+            .noargs("get_config", Self::get_config)
+            .noargs("panel_stats", Self::panel_stats)
+            .noargs("unknown_messages", Self::unknown_messages)
+            .noargs("clear_unknown", Self::clear_unknown);
+    }
+
+    fn on_call(&mut self, caller: ReplyRoute) {
+        self.in_batch = caller.batch;
     }
+}
 
+impl UartHandler {
     pub fn get_last(&mut self) -> anyhow::Result<String> {
         Ok(LAST_UART_MSG.lock().clone())
     }
 
+    pub fn send_raw(&mut self, args: SendRawArgs) -> anyhow::Result<RawReply> {
+        let SendRawArgs(data, timeout_ms) = args;
+        let bytes = match data {
+            RawData::Text(s) => s.into_bytes(),
+            RawData::Bytes(b) => b,
+        };
+
+        let frame = RawFrame::from_slice(&bytes).map_err(|_| {
+            anyhow!(
+                "Can send at most {} bytes at once, got {}.",
+                RawFrame::new().capacity(),
+                bytes.len()
+            )
+        })?;
+
+        if let Some(ms) = timeout_ms {
+            if ms > MAX_RAW_REPLY_WAIT_MS {
+                bail!("Can wait at most {MAX_RAW_REPLY_WAIT_MS}ms for a reply.");
+            }
+
+            // the rest of the batch can't wait on the panel
+            if self.in_batch {
+                bail!("Can't wait for a reply inside a batch, send the call on its own.");
+            }
+
+            if self.raw_wait.lock().is_some() {
+                bail!("Still waiting for the reply to an earlier raw write.");
+            }
+
+            uart::expect_reply();
+        }
+
+        self.uart_tx
+            .send(LightCommand::Raw(frame))
+            .map_err(|_| anyhow!("The UART sender is gone."))?;
+
+        let Some(ms) = timeout_ms else {
+            return Ok(RawReply {
+                sent: bytes.len(),
+                reply: None,
+            });
+        };
+
+        // the main loop keeps going meanwhile, the answer comes from `RpcHandler::poll`
+        *self.raw_wait.lock() = Some(RawWait {
+            sent: bytes.len(),
+            timeout_ms: ms,
+            deadline: Instant::now() + Duration::from_millis(ms as u64),
+            reply_to: None,
+        });
+
+        Err(Deferred.into())
+    }
+
+    pub fn get_config(&mut self) -> anyhow::Result<UartBusConfig> {
+        Ok(**UartBusConfig::read())
+    }
+
+    /// Applies right away and is kept across reboots.
+    pub fn configure(&mut self, args: [UartBusConfig; 1]) -> anyhow::Result<()> {
+        let [config] = args;
+        config.validate()?;
+        config.apply()?;
+        config.store()?;
+
+        Ok(())
+    }
+
+    pub fn panel_stats(&mut self) -> anyhow::Result<PanelStats> {
+        Ok(self.panel.lock().stats())
+    }
+
+    /// Lines from the panel we couldn't make sense of, with how often each was seen.
+    pub fn unknown_messages(&mut self) -> anyhow::Result<Vec<UnknownMessage>> {
+        Ok(self.panel.lock().unknown().to_vec())
+    }
+
+    pub fn clear_unknown(&mut self) -> anyhow::Result<()> {
+        self.panel.lock().clear_unknown();
+        Ok(())
+    }
+
     pub fn send(&mut self, args: [bool; 4]) -> anyhow::Result<()> {
         self.uart_tx
-            .send(Lights {
+            .send(LightCommand::Set(Lights {
                 mid_low: args[1],
                 mid_high: args[2],
                 top: args[3],
                 bottom: args[0],
-            })
-            .unwrap();
+            }))
+            .map_err(|_| anyhow!("The light controller isn't running."))?;
         Ok(())
     }
 }
+
+#[cfg(feature = "usb_pd")]
+pub struct PdHandler {
+    pub husb: Husb238Driver,
+}
+
+#[cfg(feature = "usb_pd")]
+impl RpcNamespace for PdHandler {
+    const NAME: &'static str = "pd";
+
+    fn register(methods: &mut Methods<Self>) {
+        methods
+            .method("select_pdo", Self::select_pdo)
+            .noargs("status", Self::status)
+            .noargs("capabilities", Self::capabilities);
+    }
+}
+
+#[cfg(feature = "usb_pd")]
+impl PdHandler {
+    pub fn status(&mut self) -> anyhow::Result<Status> {
+        self.husb.get_status()
+    }
+
+    pub fn capabilities(&mut self) -> anyhow::Result<Capabilities> {
+        self.husb.get_capabilities()
+    }
+
+    /// One of 5V, 9V or 12V.
+    pub fn select_pdo(&mut self, args: [String; 1]) -> anyhow::Result<()> {
+        self.husb.select_pdo(&args[0])
+    }
+}
@@ -1,26 +1,53 @@
 // use tiny_http::{Method, Response};
 
-use std::cell::RefCell;
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        mpsc::{sync_channel, SyncSender},
+        Arc,
+    },
+    time::Duration,
+};
 
+use anyhow::bail;
 use embedded_svc::http::Headers;
 use esp_idf_svc::{
+    hal::{delay::BLOCK, task::queue::Queue},
     http::{
-        server::{EspHttpConnection, EspHttpServer, Handler, Request},
+        server::{
+            ws::{EspHttpWsConnection, EspHttpWsDetachedSender},
+            EspHttpConnection, EspHttpServer, Handler, Request,
+        },
         Method,
     },
     io::{Read as _, Write as _},
     ota::{EspFirmwareInfoLoader, EspOta, FirmwareInfo},
     sys::EspError,
+    ws::FrameType,
 };
 use log::Level;
-
-use crate::rpc::{MessageSource, RpcRequester};
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::{
+    diagnostics::Fault,
+    events::{Event, OtaStatus},
+    framing::MAX_MESSAGE_LEN,
+    hal::{
+        lights::{Animation, LightCommand},
+        uart::{Direction, MonitorEntry},
+    },
+    rpc::{MessageSource, ResponseTag, RpcRequester},
+};
 
 pub fn run_http(
     http_channel: RpcRequester,
-    // ws_channel: RpcRequester,
+    ws_channel: RpcRequester,
+    lights: StaticSender<LightCommand>,
+    events: StaticSender<Event>,
+    uart_monitor: Arc<Queue<MonitorEntry>>,
     port: u16,
-    // uart_rx: Arc<Queue<ArrayString<32>>>
 ) -> anyhow::Result<EspHttpServer<'static>> {
     // let server = tiny_http::Server::http(addr).unwrap();
     let config = esp_idf_svc::http::server::Configuration {
@@ -30,7 +57,32 @@ pub fn run_http(
 
     let mut server = EspHttpServer::new(&config)?;
 
-    // let req_tx = http_channel.req_tx.clone();
+    let req_tx = http_channel.req_tx.clone();
+    let rpc_tx = http_channel.req_tx;
+
+    // the server runs handlers on several threads, so responses get routed back to whoever
+    // is waiting on their correlation id instead of going to the first one to pick them up
+    let pending: Arc<parking_lot::Mutex<HashMap<u32, SyncSender<Vec<u8>>>>> = Arc::default();
+    let next_correlation = AtomicU32::new(1);
+
+    let router_pending = Arc::clone(&pending);
+    let res_rx = http_channel.res_rx;
+    std::thread::spawn(move || {
+        while let Some(res) = res_rx.recv_ref() {
+            // answered later on, the request stays pending until then
+            if matches!(res.tag, ResponseTag::Discard) {
+                continue;
+            }
+
+            match router_pending.lock().remove(&res.correlation) {
+                Some(reply) => {
+                    let _ = reply.send(res.buffer.clone());
+                }
+                // the request timed out already
+                None => log::warn!("Dropped a late response to RPC request {}", res.correlation),
+            }
+        }
+    });
 
     server.fn_handler::<anyhow::Error, _>("/check", Method::Get, |req| {
         let mut resp = req.into_ok_response()?;
@@ -39,18 +91,51 @@ pub fn run_http(
     })?;
 
     server.fn_handler::<anyhow::Error, _>("/rpc", Method::Post, move |mut req| {
-        let mut slot = http_channel.req_tx.send_ref().unwrap();
-        slot.src = MessageSource::HttpRpc;
+        let content_len = req.content_len().unwrap_or(64) as usize;
+        if content_len > MAX_MESSAGE_LEN {
+            Fault::InvalidRequest.record();
+            respond_and_log(
+                req,
+                Level::Warn,
+                413,
+                format!("RPC request of {content_len} bytes is over {MAX_MESSAGE_LEN}"),
+            )?;
+            return Ok(());
+        }
+
+        let mut body = vec![0; content_len];
+        req.read_exact(&mut body)?;
 
-        slot.buffer
-            .resize(req.content_len().unwrap_or(64) as usize, 0);
-        req.read_exact(&mut slot.buffer)?;
+        let correlation = next_correlation.fetch_add(1, Ordering::Relaxed);
+        let (reply_tx, reply_rx) = sync_channel(1);
+        pending.lock().insert(correlation, reply_tx);
+
+        let Ok(mut slot) = rpc_tx.send_ref() else {
+            pending.lock().remove(&correlation);
+            Fault::RequestDropped.record();
+            respond_and_log(req, Level::Error, 503, "RPC queue is closed".to_owned())?;
+            return Ok(());
+        };
+        slot.src = MessageSource::HttpRpc;
+        slot.correlation = correlation;
+        slot.buffer.extend_from_slice(&body);
         drop(slot);
 
-        let res = http_channel.res_rx.recv_ref().unwrap();
+        let Ok(res) = reply_rx.recv_timeout(RPC_TIMEOUT) else {
+            pending.lock().remove(&correlation);
+            Fault::Timeout.record();
+            respond_and_log(
+                req,
+                Level::Warn,
+                504,
+                format!("RPC request {correlation} timed out"),
+            )?;
+            return Ok(());
+        };
+
         let mut resp =
             req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?;
-        resp.write_all(&res.buffer)?;
+        resp.write_all(&res)?;
         Ok(())
     })?;
 
@@ -59,27 +144,150 @@ pub fn run_http(
         Method::Post,
         FirmwareUpdateHandler {
             ota: RefCell::new(EspOta::new().unwrap()),
+            lights,
+            events: events.clone(),
+        },
+    )?;
+
+    let monitors: Arc<parking_lot::Mutex<Vec<EspHttpWsDetachedSender>>> = Arc::default();
+
+    let ws_monitors = Arc::clone(&monitors);
+    let ws_monitor_queue = Arc::clone(&uart_monitor);
+    server.ws_handler(
+        "/uart/monitor",
+        move |ws: &mut EspHttpWsConnection| -> anyhow::Result<()> {
+            if ws.is_new() {
+                log::info!("UART monitor {} connected", ws.session());
+                ws_monitors.lock().push(ws.create_detached_sender()?);
+                return Ok(());
+            }
+
+            if ws.is_closed() {
+                log::info!("UART monitor {} disconnected", ws.session());
+                return Ok(());
+            }
+
+            // anything sent to us gets handled as if the panel had sent it
+            let len = frame_len(ws)?;
+            let mut line = vec![0; len];
+            ws.recv(&mut line)?;
+
+            let line = String::from_utf8(line)?;
+            let line = line.trim();
+            MonitorEntry::new(Direction::Injected, line).record(&ws_monitor_queue);
+
+            let Ok(mut slot) = req_tx.send_ref() else {
+                Fault::RequestDropped.record();
+                bail!("The request queue closed.");
+            };
+            slot.buffer.extend_from_slice(line.as_bytes());
+            slot.src = MessageSource::Injected;
+
+            Ok(())
+        },
+    )?;
+
+    std::thread::spawn(move || loop {
+        let Some((entry, _)) = uart_monitor.recv_front(BLOCK) else {
+            continue;
+        };
+
+        let Ok(frame) = serde_json::to_vec(&entry) else {
+            continue;
+        };
+
+        // closed connections fail to send, which is when we get rid of them
+        monitors
+            .lock()
+            .retain_mut(|sender| sender.send(FrameType::Text(false), &frame).is_ok());
+    });
+
+    // calls, their answers and the events subscribed to with `sys:subscribe` all go over the
+    // same socket, routed back by session
+    let listeners: Arc<parking_lot::Mutex<HashMap<i32, EspHttpWsDetachedSender>>> = Arc::default();
+
+    let ws_listeners = Arc::clone(&listeners);
+    let ws_req_tx = ws_channel.req_tx;
+    server.ws_handler(
+        "/events",
+        move |ws: &mut EspHttpWsConnection| -> anyhow::Result<()> {
+            let connected = if ws.is_new() {
+                log::info!("Event listener {} connected", ws.session());
+                ws_listeners
+                    .lock()
+                    .insert(ws.session(), ws.create_detached_sender()?);
+                true
+            } else if ws.is_closed() {
+                log::info!("Event listener {} disconnected", ws.session());
+                ws_listeners.lock().remove(&ws.session());
+                false
+            } else {
+                let len = frame_len(ws)?;
+                let mut frame = vec![0; len];
+                ws.recv(&mut frame)?;
+
+                let Ok(mut slot) = ws_req_tx.send_ref() else {
+                    Fault::RequestDropped.record();
+                    bail!("The request queue closed.");
+                };
+                slot.buffer.extend_from_slice(&frame);
+                slot.src = MessageSource::WsRpc;
+                slot.correlation = ws.session() as u32;
+
+                return Ok(());
+            };
+
+            // also drops its event subscriptions
+            let _ = events.try_send(Event::Connection {
+                source: MessageSource::WsRpc,
+                connected,
+                client: ws.session() as u32,
+            });
+
+            Ok(())
         },
     )?;
 
-    // server.ws_handler("/uart/monitor", move |mut conn| -> anyhow::Result<()> {
-    //     while !conn.is_closed() {
-    //         let (data, _) = uart_rx.recv_front(BLOCK).unwrap();
-    //         let _ = conn.send(esp_idf_svc::ws::FrameType::Text(false), data.as_bytes())?;
-    //     }
+    let ws_res_rx = ws_channel.res_rx;
+    std::thread::spawn(move || {
+        while let Some(res) = ws_res_rx.recv_ref() {
+            if matches!(res.tag, ResponseTag::Discard) {
+                continue;
+            }
 
-    //     Ok(())
-    // })?;
+            let session = res.correlation as i32;
+            let mut listeners = listeners.lock();
+            let Some(sender) = listeners.get_mut(&session) else {
+                continue;
+            };
+
+            // closed connections fail to send, which is when we get rid of them
+            if sender.send(FrameType::Text(false), &res.buffer).is_err() {
+                listeners.remove(&session);
+            }
+        }
+    });
 
     Ok(server)
 }
 
+// how long `/rpc` waits on the main loop before giving up with a 504
+const RPC_TIMEOUT: Duration = Duration::from_secs(5);
+
 const FIRMWARE_DOWNLOAD_CHUNK_SIZE: usize = 1024 * 8; // 8kb
 const FIRMWARE_MAX_SIZE: usize = 1024 * 1024 * 3; // 3MB
 const FIRMWARE_MIN_SIZE: usize = size_of::<FirmwareInfo>() + 1024;
 
 pub struct FirmwareUpdateHandler {
     ota: RefCell<EspOta>,
+    lights: StaticSender<LightCommand>,
+    events: StaticSender<Event>,
+}
+
+impl FirmwareUpdateHandler {
+    fn progress(&self, percent: u8, status: OtaStatus) {
+        let _ = self.events.try_send(Event::Ota { percent, status });
+    }
 }
 
 impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
@@ -128,6 +336,7 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
         let mut buffer = vec![0; FIRMWARE_DOWNLOAD_CHUNK_SIZE];
         let mut missing_firmware_info = true;
         let mut total_bytes_read = 0;
+        let mut progress = None;
 
         let dl_result = loop {
             let Ok(bytes_read) = req.read(&mut buffer) else {
@@ -140,6 +349,16 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
             );
 
             total_bytes_read += bytes_read;
+
+            let pct = (total_bytes_read * 100 / file_size).min(100) as u8;
+            if progress != Some(pct) {
+                progress = Some(pct);
+                let _ = self
+                    .lights
+                    .send(LightCommand::Play(Animation::Progress(pct)));
+                self.progress(pct, OtaStatus::InProgress);
+            }
+
             if missing_firmware_info {
                 let Ok(_info) = get_firmware_info(&buffer[..bytes_read]) else {
                     break Err((
@@ -163,19 +382,36 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
             }
         };
 
+        let pct = progress.unwrap_or(0);
         if let Err((status, err_msg)) = dl_result {
-            work.abort().unwrap();
+            if let Err(e) = work.abort() {
+                log::error!("Failed to abort the OTA update: {e}");
+            }
+            let _ = self.lights.send(LightCommand::Play(Animation::Error));
+            self.progress(pct, OtaStatus::Failed);
             respond_and_log(req, Level::Error, status, err_msg)?;
             return Ok(());
         }
 
         if total_bytes_read < file_size {
-            work.abort().unwrap();
+            if let Err(e) = work.abort() {
+                log::error!("Failed to abort the OTA update: {e}");
+            }
+            let _ = self.lights.send(LightCommand::Play(Animation::Error));
+            self.progress(pct, OtaStatus::Failed);
             respond_and_log(req, Level::Error, 500, format!("was supposed to get {file_size} bytes, but only got {total_bytes_read}. aborting update"))?;
             return Ok(());
         }
 
-        work.complete()?;
+        if let Err(e) = work.complete() {
+            let _ = self.lights.send(LightCommand::Play(Animation::Error));
+            self.progress(pct, OtaStatus::Failed);
+            return Err(e.into());
+        }
+        let _ = self
+            .lights
+            .send(LightCommand::Stop(Animation::Progress(100)));
+        self.progress(100, OtaStatus::Done);
 
         respond_and_log(req, Level::Info, 200, "OTA update completed!".to_owned())?;
 
@@ -183,6 +419,20 @@ impl Handler<EspHttpConnection<'_>> for FirmwareUpdateHandler {
     }
 }
 
+/// Length of the frame waiting on `ws`. Anything bigger than an RPC message closes the socket
+/// instead of getting a buffer that size.
+fn frame_len(ws: &mut EspHttpWsConnection) -> anyhow::Result<usize> {
+    let (_, len) = ws.recv(&mut [])?;
+    if len > MAX_MESSAGE_LEN {
+        Fault::InvalidRequest.record();
+        // 1009: message too big
+        let _ = ws.send(FrameType::Close, &1009u16.to_be_bytes());
+        bail!("Websocket frame of {len} bytes is over the limit of {MAX_MESSAGE_LEN}.");
+    }
+
+    Ok(len)
+}
+
 fn respond_and_log(
     r: Request<&mut EspHttpConnection>,
     log_level: log::Level,
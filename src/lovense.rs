@@ -0,0 +1,70 @@
+use std::ops::Range;
+
+use anyhow::bail;
+
+// strengths go from 0 to 20 in `Vibrate:<strength>;`
+pub const MAX_STRENGTH: u8 = 20;
+
+/// A command from a Lovense app, like `Vibrate:10;`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LovenseCommand {
+    Battery,
+    Status,
+    GetLight,
+    Vibrate(u8),
+    // anything else gets an empty reply, which keeps the apps happy
+    Other,
+}
+
+impl LovenseCommand {
+    pub fn parse(msg: &[u8]) -> anyhow::Result<Self> {
+        let msg = std::str::from_utf8(msg)?;
+        let Some((msg, _)) = msg.split_once(';') else {
+            bail!("Lovense command {msg:?} is missing its ';'.");
+        };
+
+        let mut args = msg.split(':');
+        let command = match args.next().unwrap_or_default() {
+            "Battery" => LovenseCommand::Battery,
+            "Status" => LovenseCommand::Status,
+            "GetLight" => LovenseCommand::GetLight,
+            "Vibrate" => {
+                let Some(strength) = args.next() else {
+                    bail!("Vibrate needs a strength.");
+                };
+
+                let strength: u8 = strength.parse()?;
+                if strength > MAX_STRENGTH {
+                    bail!("Vibrate strength {strength} is over {MAX_STRENGTH}.");
+                }
+
+                LovenseCommand::Vibrate(strength)
+            }
+            _ => LovenseCommand::Other,
+        };
+
+        Ok(command)
+    }
+
+    /// What the command gets answered with, as `:` separated fields.
+    pub fn reply(self) -> &'static [&'static str] {
+        match self {
+            LovenseCommand::Battery => &["100"],
+            LovenseCommand::Status => &["2"],
+            LovenseCommand::GetLight => &["Light", "1"],
+            LovenseCommand::Vibrate(_) | LovenseCommand::Other => &[],
+        }
+    }
+}
+
+/// Where a `Vibrate` strength lands in `target`, the range set with `wand:update_lovense_mapping`.
+/// 0 is always off.
+pub fn map_strength(strength: u8, target: Range<i64>) -> i64 {
+    if strength == 0 {
+        return 0;
+    }
+
+    // the mapping is whatever the client stored, widened so no range of it can overflow
+    let (start, end) = (target.start as i128, target.end as i128);
+    (start + strength as i128 * (end - start) / MAX_STRENGTH as i128) as i64
+}
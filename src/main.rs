@@ -3,11 +3,18 @@
 use std::{
     ffi::CString,
     rc::Rc,
-    sync::{atomic::AtomicBool, Arc},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
 use ble::run_ble;
+use buttons::ButtonHandler;
 use config::ConfigType;
+use control::ControlArbiter;
+use diagnostics::Fault;
 use esp_idf_hal::{gpio, task::queue::Queue, uart::UartDriver};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
@@ -22,24 +29,53 @@ use esp_idf_svc::{
     wifi::EspWifi,
 };
 #[cfg(feature = "usb_pd")]
+use esp_idf_svc::hal::i2c::{I2cConfig, I2cDriver};
+use events::{EventBus, EVENT_QUEUE};
+#[cfg(feature = "usb_pd")]
 use hal::husb238::Husb238Driver;
 use hal::{
-    uart::spawn_uart_thread,
-    wand::{Lights, Wand},
+    lights::{Animation, LightCommand},
+    panel::{PanelMessage, PanelMonitor},
+    timer::{spawn_timer_thread, TICK_PENDING},
+    uart::{spawn_uart_thread, UartBusConfig},
+    wand::{Lights, MotorProfile, Wand},
 };
+#[cfg(feature = "usb_pd")]
+use handlers::rpc::PdHandler;
 use handlers::{lovense::LovenseHandler, rpc::RpcHandler};
 use http::run_http;
-use rpc::{ChannelOptions, MessageSource, ResponseTag, RpcCall, RpcResponse, REQUEST_QUEUE};
+use pattern::PatternPlayer;
+use rpc::{
+    ChannelOptions, Encoding, MessageRecycler, MessageSource, ReplyRoute, RequestMessage,
+    ResponseTag,
+};
 use serde::Serialize;
+use session::SessionGuard;
+use stats::StatsTracker;
+use thermal::ThermalSupervisor;
+use thingbuf::mpsc::blocking::StaticChannel;
 use wifi::{WifiConfig, WifiManager};
 // use script::ScriptRunner;
 
 mod ble;
+mod buttons;
 mod config;
+mod control;
+mod diagnostics;
+mod events;
+mod framing;
+mod gesture;
 mod hal;
 mod handlers;
 mod http;
+mod lovense;
+mod pattern;
+mod registry;
 mod rpc;
+mod schema;
+mod session;
+mod stats;
+mod thermal;
 mod wifi;
 
 #[derive(Serialize, Copy, Clone)]
@@ -52,6 +88,8 @@ pub struct BuildInfo {
     pub crate_version: &'static str,
 }
 
+impl_rpc_schema!(BuildInfo => BUILD_INFO);
+
 impl BuildInfo {
     pub const fn make() -> Self {
         Self {
@@ -67,14 +105,24 @@ impl BuildInfo {
 
 const BUILD_INFO: BuildInfo = BuildInfo::make();
 
+// every request from every transport, handled one by one by the main loop
+pub static REQUEST_QUEUE: StaticChannel<RequestMessage, 32, MessageRecycler> =
+    StaticChannel::<RequestMessage, 32, MessageRecycler>::with_recycle(MessageRecycler::new(
+        32, 512,
+    ));
+
 pub static LAST_UART_MSG: parking_lot::Mutex<String> = parking_lot::Mutex::new(String::new());
 pub static UPDATE_MAPPINGS: AtomicBool = AtomicBool::new(false);
 
+#[cfg(feature = "usb_pd")]
+const PD_POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
 
     let (req_tx, req_rx) = REQUEST_QUEUE.split();
+    let (event_tx, event_rx) = EVENT_QUEUE.split();
 
     let (ble_tx, ble_res_tx) = rpc::make_channel(
         req_tx.clone(),
@@ -93,6 +141,15 @@ fn main() -> anyhow::Result<()> {
         },
     );
 
+    let (ws_tx, ws_res_tx) = rpc::make_channel(
+        req_tx.clone(),
+        ChannelOptions {
+            message_capacity: 8,
+            min_buffer_size: 64,
+            max_buffer_size: 512,
+        },
+    );
+
     let (uart_requester, _uart_res_tx) = rpc::make_channel(
         req_tx.clone(),
         ChannelOptions {
@@ -114,24 +171,7 @@ fn main() -> anyhow::Result<()> {
     let sys_loop = EspSystemEventLoop::take()?;
     let default_nvs = EspDefaultNvsPartition::take()?;
 
-    let uart_tx = peripherals.pins.gpio22;
-    let uart_rx = peripherals.pins.gpio23;
-
-    let config = esp_idf_hal::uart::config::Config::new().baudrate(Hertz(9600));
-    let uart: UartDriver<'static> = UartDriver::new(
-        peripherals.uart1,
-        uart_tx,
-        uart_rx,
-        Option::<gpio::Gpio0>::None,
-        Option::<gpio::Gpio1>::None,
-        &config,
-    )?;
-
-    let uart_queue = Arc::new(Queue::new(16));
-
-    let (_uartrx_thread, _uarttx_thread, uart_tx) =
-        spawn_uart_thread(uart_requester, uart, Arc::clone(&uart_queue));
-
+    // mounted before anything else, the uart config lives on it too
     unsafe {
         let base_path = CString::new("/littlefs").unwrap();
         let storage = CString::new("storage").unwrap();
@@ -149,6 +189,28 @@ fn main() -> anyhow::Result<()> {
         esp_nofail!(esp_vfs_littlefs_register(&conf));
     }
 
+    if let Err(e) = std::fs::create_dir_all(pattern::PATTERN_DIR) {
+        log::error!("Failed to create pattern directory: {e}");
+    }
+
+    let uart_tx = peripherals.pins.gpio22;
+    let uart_rx = peripherals.pins.gpio23;
+
+    let config = UartBusConfig::read().driver_config();
+    let uart: UartDriver<'static> = UartDriver::new(
+        peripherals.uart1,
+        uart_tx,
+        uart_rx,
+        Option::<gpio::Gpio0>::None,
+        Option::<gpio::Gpio1>::None,
+        &config,
+    )?;
+
+    let uart_queue = Arc::new(Queue::new(32));
+
+    let (_uartrx_thread, _uarttx_thread, uart_tx) =
+        spawn_uart_thread(uart_requester, uart, Arc::clone(&uart_queue));
+
     let wifi = WifiManager::new(
         EspWifi::new(peripherals.modem, sys_loop.clone(), Some(default_nvs))?,
         sys_loop,
@@ -158,9 +220,13 @@ fn main() -> anyhow::Result<()> {
         log::error!("Failed to set wifi config: {e}");
     }
 
+    let _ = uart_tx.send(LightCommand::Play(Animation::Chase));
     if let Err(e) = wifi.start() {
         log::error!("Failed to start wifi: {e}");
-    };
+        let _ = uart_tx.send(LightCommand::Play(Animation::Error));
+    } else {
+        let _ = uart_tx.send(LightCommand::Stop(Animation::Chase));
+    }
 
     let mut mdns = EspMdns::take()?;
 
@@ -172,7 +238,7 @@ fn main() -> anyhow::Result<()> {
     temp_sensor.enable().unwrap();
 
     #[cfg(feature = "usb_pd")]
-    let husb = Husb238Driver {
+    let mut husb = Husb238Driver {
         i2c: Rc::new(parking_lot::Mutex::new(I2cDriver::new(
             peripherals.i2c0,
             peripherals.pins.gpio6,
@@ -183,7 +249,7 @@ fn main() -> anyhow::Result<()> {
 
     let timer_driver = LedcTimerDriver::new(
         peripherals.ledc.timer0,
-        &TimerConfig::default().frequency(5.kHz().into()),
+        &TimerConfig::default().frequency(Hertz(MotorProfile::read().frequency)),
     )
     .unwrap();
 
@@ -193,134 +259,241 @@ fn main() -> anyhow::Result<()> {
         peripherals.pins.gpio10,
     )?;
 
-    let pwm_controller = Rc::new(parking_lot::Mutex::new(Wand {
-        percent: 0,
-        driver: ledc_driver,
-        uart_tx: uart_tx.clone(),
-    }));
+    let pwm_controller = Rc::new(parking_lot::Mutex::new(Wand::new(
+        ledc_driver,
+        uart_tx.clone(),
+    )));
+
+    let patterns = Rc::new(parking_lot::Mutex::new(PatternPlayer::default()));
+    let control = Rc::new(parking_lot::Mutex::new(ControlArbiter::default()));
+    let session = Rc::new(parking_lot::Mutex::new(SessionGuard::default()));
+    let stats = Rc::new(parking_lot::Mutex::new(StatsTracker::load()));
+    let panel = Rc::new(parking_lot::Mutex::new(PanelMonitor::default()));
+    let events = Rc::new(parking_lot::Mutex::new(EventBus::new(
+        ble_res_tx.clone(),
+        ws_res_tx.clone(),
+    )));
+    let thermal = Rc::new(parking_lot::Mutex::new(ThermalSupervisor::new(
+        temp_sensor,
+        uart_tx.clone(),
+    )));
 
     let mut lovense_handler = LovenseHandler {
         pwm: Rc::clone(&pwm_controller),
+        patterns: Rc::clone(&patterns),
+        control: Rc::clone(&control),
     };
 
+    let mut button_handler = ButtonHandler::new(
+        Rc::clone(&pwm_controller),
+        Rc::clone(&patterns),
+        Rc::clone(&control),
+        Rc::clone(&session),
+    );
+
     // leds, pwm_t, pwm_d0, d_2, d_4
-    let _ = uart_tx.send(Lights {
+    let _ = uart_tx.send(LightCommand::Set(Lights {
         bottom: true,
         ..Default::default()
-    });
+    }));
 
     // uart_tx.send("1111,".to_string()).unwrap();
     let mut rpc_handler = RpcHandler::new(
         Rc::clone(&pwm_controller),
-        temp_sensor,
+        Rc::clone(&patterns),
+        Rc::clone(&control),
+        Rc::clone(&thermal),
+        Rc::clone(&session),
+        Rc::clone(&stats),
+        Rc::clone(&panel),
+        Rc::clone(&events),
         wifi,
         uart_tx.clone(),
         req_tx.clone()
     );
 
-    let _ble_thread = std::thread::spawn(|| run_ble(ble_tx));
-    let _http_server = run_http(http_tx, 8080);
+    #[cfg(feature = "usb_pd")]
+    rpc_handler.register(PdHandler { husb: husb.clone() });
+    #[cfg(feature = "usb_pd")]
+    let mut pd_polled_at = Instant::now();
+
+    let ble_lights = uart_tx.clone();
+    let ble_events = event_tx.clone();
+    // for answering what can't even be queued up as a request
+    let ble_responses = ble_res_tx.clone();
+    let _ble_thread =
+        std::thread::spawn(|| run_ble(ble_tx, ble_responses, ble_lights, ble_events));
+    let _http_server = run_http(
+        http_tx,
+        ws_tx,
+        uart_tx.clone(),
+        event_tx,
+        Arc::clone(&uart_queue),
+        8080,
+    );
+    let _timer_thread = spawn_timer_thread(req_tx.clone());
 
     loop {
-        let message = req_rx.recv_ref().unwrap();
+        let Some(message) = req_rx.recv_ref() else {
+            // every sender is gone, nothing will ever show up again
+            anyhow::bail!("The request queue closed.");
+        };
+        if matches!(
+            message.src,
+            MessageSource::BleRpc
+                | MessageSource::HttpRpc
+                | MessageSource::WsRpc
+                | MessageSource::BleLovense
+        ) {
+            session.lock().activity(Instant::now());
+        }
+
         let mut response_tag: ResponseTag = ResponseTag::Normal; // tags the response with a certain value at the end of the buffer
 
         let res_channel = match message.src {
             MessageSource::BleRpc => {
-                response_tag = ResponseTag::BleRpc;
+                response_tag = match message.encoding {
+                    Encoding::Json => ResponseTag::BleRpc,
+                    Encoding::MsgPack => ResponseTag::BleMsgPack,
+                };
                 &ble_res_tx
             }
             MessageSource::HttpRpc => &http_res_tx,
+            MessageSource::WsRpc => &ws_res_tx,
             MessageSource::BleLovense => {
-                lovense_handler.handle(
-                    std::str::from_utf8(&message.buffer).unwrap(),
-                    ble_res_tx.send_ref().unwrap(),
-                );
+                match ble_res_tx.send_ref() {
+                    Ok(slot) => lovense_handler.handle(&message.buffer, slot),
+                    Err(_) => Fault::ResponseDropped.record(),
+                }
 
                 continue;
             }
-            MessageSource::Uart => {
-                let msg = String::from_utf8_lossy(&message.buffer).into_owned();
-                *LAST_UART_MSG.lock() = msg.clone();
+            MessageSource::Timer => {
+                TICK_PENDING.store(false, Ordering::Release);
 
-                let msg_trimmed = msg.trim();
-                if !msg_trimmed.starts_with("BUTTONS") {
-                    continue;
-                }
+                let now = Instant::now();
+                button_handler.tick(now);
 
+                let mut wand = pwm_controller.lock();
+                let mut player = patterns.lock();
+                if let Some(pct) = player.tick(now) {
+                    wand.set_percent(pct);
+                }
+                wand.set_pattern_step(player.step());
+                drop(player);
+
+                let mut supervisor = thermal.lock();
+                supervisor.tick(now, &mut wand);
+                stats
+                    .lock()
+                    .tick(now, wand.get_actual(), supervisor.temperature());
+                let thermal_state = supervisor.state();
+                drop(supervisor);
+
+                let pattern_playing = patterns.lock().current().is_some();
+                let mut guard = session.lock();
+                let session_ended = guard.tick(now, &mut wand, pattern_playing).is_some();
+                stats.lock().session(guard.running());
+                drop(guard);
+
+                if wand.is_faulted() || session_ended {
+                    patterns.lock().stop();
+                }
 
-                if let Some((_, state)) = msg_trimmed.split_once(':') {
-                    // state.by
-                    let button_states = [
-                        state.as_bytes()[0] == b'0',
-                        state.as_bytes()[1] == b'0',
-                        state.as_bytes()[2] == b'0',
-                    ];
-
-                    let mut wand = pwm_controller.lock();
-                    let cur = wand.get_percent();
-                    if button_states[0] {
-                        wand.set_percent(cur - 25);
-                    } else if button_states[1] {
-                        wand.set_percent(cur + 25);
-                    } else if button_states[2] {
-                        wand.set_percent(0);
+                wand.tick(now);
+
+                // calls that were waiting on something, like a raw write on the panel's reply
+                if let Some((route, res)) = rpc_handler.poll(now) {
+                    let target = match (route.src, route.encoding) {
+                        (MessageSource::BleRpc, Encoding::Json) => {
+                            Some((&ble_res_tx, ResponseTag::BleRpc))
+                        }
+                        (MessageSource::BleRpc, Encoding::MsgPack) => {
+                            Some((&ble_res_tx, ResponseTag::BleMsgPack))
+                        }
+                        (MessageSource::HttpRpc, _) => Some((&http_res_tx, ResponseTag::Normal)),
+                        (MessageSource::WsRpc, _) => Some((&ws_res_tx, ResponseTag::Normal)),
+                        _ => None,
+                    };
+
+                    match target.map(|(channel, tag)| (channel.send_ref(), tag)) {
+                        Some((Ok(mut slot), tag)) => {
+                            slot.tag = tag;
+                            slot.correlation = route.correlation;
+                            if let Err(e) = route.encoding.write(&mut slot.buffer, &res) {
+                                log::error!("Failed to write a late RPC response: {e}");
+                                slot.buffer.clear();
+                                slot.tag = ResponseTag::Discard;
+                            }
+                        }
+                        Some((Err(_), _)) => Fault::ResponseDropped.record(),
+                        None => {
+                            log::error!(
+                                "Nowhere to send a late response to a {:?} request",
+                                route.src
+                            );
+                            Fault::ResponseDropped.record();
+                        }
                     }
-                    // if let Err(e) = script.handle(button_states) {
-                    //     log::error!("on handling script: {e}");
-                    // }
+                }
 
+                let mut bus = events.lock();
+                bus.intensity(now, wand.get_percent(), wand.get_actual());
+                bus.thermal(thermal_state);
+                bus.drain(&event_rx);
+
+                #[cfg(feature = "usb_pd")]
+                if now.duration_since(pd_polled_at) >= PD_POLL_INTERVAL {
+                    pd_polled_at = now;
+                    match husb.get_status() {
+                        Ok(status) => bus.pd(status.attached),
+                        Err(e) => log::warn!("Failed to read the USB PD status: {e}"),
+                    }
                 }
 
                 continue;
-
-                //     let delta = if button_states[1] {
-                //         Some(hitachi_mappings.button_mappings[0])
-                //     } else if button_states[2] {
-                //         Some(hitachi_mappings.button_mappings[1])
-                //     } else {
-                //         None
-                //     };
-
-                //     if let Some(delta) = delta {
-                //         let mut pwm = pwm_controller.lock();
-                //         let pct = pwm.get_percent() + delta;
-                //         pwm.set_percent(pct);
-
-                //         let lights = Lights::from_mapping(pct, &hitachi_mappings.light_mappings);
-
-                //         let mut slot = uart_tx.send_ref().unwrap();
-                //         slot.clear();
-                //         lights.write_into(&mut slot);
-                //         drop(slot);
-                //     }
-                // }
-
-                // continue;
             }
-        };
+            MessageSource::Uart | MessageSource::Injected => {
+                *LAST_UART_MSG.lock() = String::from_utf8_lossy(&message.buffer).into_owned();
 
-        let mut slot = res_channel.send_ref().unwrap();
-        slot.tag = response_tag;
+                let parsed = panel.lock().handle(&message.buffer);
+                if let Some(PanelMessage::Buttons(pressed)) = parsed {
+                    events.lock().buttons(pressed);
+                    button_handler.update(pressed, message.src, Instant::now());
+                }
 
-        let request: RpcCall<'_> = match serde_json::from_slice(&message.buffer) {
-            Ok(v) => v,
-            Err(e) => {
-                log::error!("Invalid RPC request: {e}");
-                serde_json::to_writer(
-                    &mut slot.buffer,
-                    &RpcResponse::new::<(), anyhow::Error>(
-                        0,
-                        anyhow::Result::Err(anyhow::anyhow!("Invalid JSON in RPC request: {}", e)),
-                    ),
-                )
-                .unwrap();
                 continue;
             }
         };
 
-        if let Err(e) = rpc_handler.rpc_call(request, &mut slot.buffer) {
-            log::error!("RPC handler error: {e}");
+        let Ok(mut slot) = res_channel.send_ref() else {
+            log::error!(
+                "Nowhere to send the response to a {:?} request",
+                message.src
+            );
+            Fault::ResponseDropped.record();
+            continue;
+        };
+        slot.tag = response_tag;
+        slot.correlation = message.correlation;
+
+        let route = ReplyRoute {
+            src: message.src,
+            encoding: message.encoding,
+            correlation: message.correlation,
+            batch: false,
+        };
+        let answered_later = rpc_handler
+            .handle_message(&message.buffer, route, &mut slot.buffer)
+            .unwrap_or_else(|e| {
+                log::error!("RPC handler error: {e}");
+                false
+            });
+
+        // nothing to answer yet. after only notifications, http still gets the empty slot so it
+        // can reply
+        if slot.buffer.is_empty() && (answered_later || message.src != MessageSource::HttpRpc) {
+            slot.tag = ResponseTag::Discard;
         }
 
         drop(slot);
@@ -0,0 +1,363 @@
+use std::{path::PathBuf, time::Instant};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+
+use crate::impl_rpc_schema;
+
+pub const PATTERN_DIR: &str = "/littlefs/patterns";
+
+const MAX_NAME_LEN: usize = 32;
+const MAX_KEYFRAMES: usize = 64;
+const MAX_KEYFRAME_DURATION_MS: u32 = 60_000;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation {
+    #[default]
+    Step,
+    Linear,
+}
+
+impl_rpc_schema!(Interpolation => ["step", "linear"]);
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Keyframe {
+    pub intensity: i64,
+    pub duration_ms: u32,
+    // how to get from this keyframe's intensity to the next one's
+    #[serde(default)]
+    pub interpolation: Interpolation,
+}
+
+impl_rpc_schema!(Keyframe => { intensity: i64, duration_ms: u32, interpolation: Interpolation });
+
+impl Keyframe {
+    const fn new(intensity: i64, duration_ms: u32, interpolation: Interpolation) -> Self {
+        Keyframe {
+            intensity,
+            duration_ms,
+            interpolation,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Pattern {
+    pub keyframes: Vec<Keyframe>,
+    #[serde(default)]
+    pub repeat: bool,
+}
+
+impl_rpc_schema!(Pattern => { keyframes: Vec<Keyframe>, repeat: bool });
+
+impl Pattern {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.keyframes.is_empty() {
+            bail!("Pattern has no keyframes.");
+        }
+
+        if self.keyframes.len() > MAX_KEYFRAMES {
+            bail!(
+                "Pattern has {} keyframes, at most {MAX_KEYFRAMES} are allowed.",
+                self.keyframes.len()
+            );
+        }
+
+        for (idx, frame) in self.keyframes.iter().enumerate() {
+            if !(0..=100).contains(&frame.intensity) {
+                bail!(
+                    "Keyframe {idx}: intensity {} is outside of 0..=100.",
+                    frame.intensity
+                );
+            }
+
+            if frame.duration_ms > MAX_KEYFRAME_DURATION_MS {
+                bail!(
+                    "Keyframe {idx}: duration {}ms is longer than the maximum of {MAX_KEYFRAME_DURATION_MS}ms.",
+                    frame.duration_ms
+                );
+            }
+        }
+
+        if self.duration_ms() == 0 {
+            bail!("Pattern has a total duration of 0ms.");
+        }
+
+        Ok(())
+    }
+
+    pub fn duration_ms(&self) -> u64 {
+        self.keyframes.iter().map(|k| k.duration_ms as u64).sum()
+    }
+
+    /// Where in the pattern we are `elapsed_ms` after starting it.
+    pub fn position_at(&self, elapsed_ms: u64) -> PatternPosition {
+        let Some(last) = self.keyframes.last() else {
+            return PatternPosition {
+                intensity: 0,
+                step: 0,
+                finished: true,
+            };
+        };
+
+        let end = PatternPosition {
+            intensity: last.intensity,
+            step: self.keyframes.len() - 1,
+            finished: true,
+        };
+
+        let total = self.duration_ms();
+        if total == 0 {
+            return end;
+        }
+
+        let mut t = if self.repeat {
+            elapsed_ms % total
+        } else if elapsed_ms >= total {
+            return end;
+        } else {
+            elapsed_ms
+        };
+
+        for (idx, frame) in self.keyframes.iter().enumerate() {
+            let duration = frame.duration_ms as u64;
+            if t >= duration {
+                t -= duration;
+                continue;
+            }
+
+            let intensity = match frame.interpolation {
+                Interpolation::Step => frame.intensity,
+                Interpolation::Linear => {
+                    let next = match self.keyframes.get(idx + 1) {
+                        Some(next) => next.intensity,
+                        None if self.repeat => self.keyframes[0].intensity,
+                        None => frame.intensity,
+                    };
+
+                    frame.intensity + (next - frame.intensity) * t as i64 / duration as i64
+                }
+            };
+
+            return PatternPosition {
+                intensity,
+                step: idx,
+                finished: false,
+            };
+        }
+
+        PatternPosition {
+            finished: !self.repeat,
+            ..end
+        }
+    }
+}
+
+pub struct PatternPosition {
+    pub intensity: i64,
+    // index of the keyframe we're in
+    pub step: usize,
+    pub finished: bool,
+}
+
+pub const BUILTIN_PATTERNS: &[&str] = &["pulse", "wave", "ramp", "escalate"];
+
+pub fn builtin(name: &str) -> Option<Pattern> {
+    use Interpolation::*;
+
+    let (keyframes, repeat) = match name {
+        "pulse" => (
+            vec![Keyframe::new(100, 300, Step), Keyframe::new(0, 300, Step)],
+            true,
+        ),
+        "wave" => (
+            vec![
+                Keyframe::new(20, 1500, Linear),
+                Keyframe::new(100, 1500, Linear),
+            ],
+            true,
+        ),
+        "ramp" => (
+            vec![Keyframe::new(0, 10_000, Linear), Keyframe::new(100, 0, Step)],
+            false,
+        ),
+        "escalate" => (
+            vec![
+                Keyframe::new(30, 400, Step),
+                Keyframe::new(0, 400, Step),
+                Keyframe::new(50, 400, Step),
+                Keyframe::new(0, 400, Step),
+                Keyframe::new(70, 400, Step),
+                Keyframe::new(0, 400, Step),
+                Keyframe::new(100, 400, Step),
+                Keyframe::new(0, 400, Step),
+            ],
+            true,
+        ),
+        _ => return None,
+    };
+
+    Some(Pattern { keyframes, repeat })
+}
+
+/// Names of every available pattern, built-in ones first.
+pub fn names() -> anyhow::Result<Vec<String>> {
+    let mut names: Vec<String> = BUILTIN_PATTERNS.iter().map(|s| s.to_string()).collect();
+    names.extend(store::list()?);
+
+    Ok(names)
+}
+
+/// Loads a pattern by name, checking the built-in ones before the ones stored on flash.
+pub fn load(name: &str) -> anyhow::Result<Pattern> {
+    match builtin(name) {
+        Some(pattern) => Ok(pattern),
+        None => store::load(name),
+    }
+}
+
+pub mod store {
+    use super::*;
+
+    fn path_for(name: &str) -> anyhow::Result<PathBuf> {
+        validate_name(name)?;
+        Ok(PathBuf::from(PATTERN_DIR).join(format!("{name}.json")))
+    }
+
+    fn validate_name(name: &str) -> anyhow::Result<()> {
+        if name.is_empty() || name.len() > MAX_NAME_LEN {
+            bail!("Pattern names must be between 1 and {MAX_NAME_LEN} characters long.");
+        }
+
+        if !name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        {
+            bail!("Pattern names may only contain letters, digits, '-' and '_'.");
+        }
+
+        if BUILTIN_PATTERNS.contains(&name) {
+            bail!("{name} is a built-in pattern.");
+        }
+
+        Ok(())
+    }
+
+    pub fn list() -> anyhow::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(PATTERN_DIR)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(stem) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(stem.to_owned());
+                }
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    pub fn load(name: &str) -> anyhow::Result<Pattern> {
+        let path = path_for(name)?;
+        if !std::fs::exists(&path)? {
+            bail!("Unknown pattern: {name}");
+        }
+
+        let f = std::fs::File::open(path)?;
+        Ok(serde_json::from_reader(f)?)
+    }
+
+    pub fn save(name: &str, pattern: &Pattern) -> anyhow::Result<()> {
+        let path = path_for(name)?;
+        pattern.validate()?;
+
+        let f = std::fs::File::create(path)?;
+        serde_json::to_writer(f, pattern)?;
+        Ok(())
+    }
+
+    pub fn rename(from: &str, to: &str) -> anyhow::Result<()> {
+        let (from_path, to_path) = (path_for(from)?, path_for(to)?);
+        if !std::fs::exists(&from_path)? {
+            bail!("Unknown pattern: {from}");
+        }
+
+        if std::fs::exists(&to_path)? {
+            bail!("A pattern named {to} already exists.");
+        }
+
+        std::fs::rename(from_path, to_path)?;
+        Ok(())
+    }
+
+    pub fn delete(name: &str) -> anyhow::Result<()> {
+        let path = path_for(name)?;
+        if !std::fs::exists(&path)? {
+            bail!("Unknown pattern: {name}");
+        }
+
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+}
+
+struct Playback {
+    name: String,
+    pattern: Pattern,
+    started_at: Instant,
+    last_output: Option<i64>,
+    step: usize,
+}
+
+/// Steps through the currently playing pattern. Driven by the timer ticks in the main loop,
+/// so it keeps going regardless of which clients are connected.
+#[derive(Default)]
+pub struct PatternPlayer {
+    playing: Option<Playback>,
+}
+
+impl PatternPlayer {
+    pub fn play(&mut self, name: String, pattern: Pattern, now: Instant) {
+        self.playing = Some(Playback {
+            name,
+            pattern,
+            started_at: now,
+            last_output: None,
+            step: 0,
+        });
+    }
+
+    pub fn stop(&mut self) -> bool {
+        self.playing.take().is_some()
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.playing.as_ref().map(|p| p.name.as_str())
+    }
+
+    /// Index of the keyframe the playing pattern is at.
+    pub fn step(&self) -> Option<usize> {
+        self.playing.as_ref().map(|p| p.step)
+    }
+
+    /// Returns the intensity the wand should move to, or `None` if it hasn't changed since the last tick.
+    pub fn tick(&mut self, now: Instant) -> Option<i64> {
+        let playback = self.playing.as_mut()?;
+        let elapsed = now.duration_since(playback.started_at).as_millis() as u64;
+        let position = playback.pattern.position_at(elapsed);
+
+        let changed = playback.last_output != Some(position.intensity);
+        playback.last_output = Some(position.intensity);
+        playback.step = position.step;
+
+        if position.finished {
+            self.playing = None;
+        }
+
+        changed.then_some(position.intensity)
+    }
+}
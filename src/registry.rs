@@ -0,0 +1,601 @@
+use std::rc::Rc;
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{value::RawValue, Value};
+
+use crate::{
+    diagnostics::Fault,
+    rpc::{
+        Encoding, ReplyRoute, RpcCall, RpcError, RpcId, RpcResponse, RpcVersion, INTERNAL_ERROR,
+        INVALID_PARAMS, INVALID_REQUEST, METHOD_NOT_FOUND, PARSE_ERROR,
+    },
+    schema::{describe_method, describe_noargs_method, RpcSchema},
+};
+
+/// Descriptions of every registered method, filled in as namespaces get registered.
+pub type MethodIndex = Rc<parking_lot::Mutex<Vec<Value>>>;
+
+/// A group of RPC methods under a common prefix, like `sys` in `sys:health`.
+pub trait RpcNamespace: Sized + 'static {
+    const NAME: &'static str;
+
+    fn register(methods: &mut Methods<Self>);
+
+    /// Called before every method of the namespace, with where the call came from.
+    fn on_call(&mut self, _caller: ReplyRoute) {}
+}
+
+/// Returned by methods that can't answer right away. Whoever parked the call answers it later
+/// through the [`PendingCall`] the registry hands out for it.
+#[derive(Debug)]
+pub struct Deferred;
+
+impl std::fmt::Display for Deferred {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("The call gets answered later.")
+    }
+}
+
+impl std::error::Error for Deferred {}
+
+/// The caller of a deferred method, everything needed to answer it later on.
+pub struct PendingCall {
+    version: RpcVersion,
+    id: Option<RpcId>,
+}
+
+impl PendingCall {
+    pub fn answer<T: Serialize, E: ToString>(&self, res: Result<T, E>) -> RpcResponse {
+        RpcResponse::answer(self.version, self.id.clone(), res)
+    }
+}
+
+// `None` when the method deferred its answer
+type Handler<H> = Box<dyn Fn(&mut H, &RpcCall<'_>) -> Option<RpcResponse>>;
+
+fn respond<R: Serialize>(call: &RpcCall<'_>, res: anyhow::Result<R>) -> Option<RpcResponse> {
+    match res {
+        Err(e) if e.is::<Deferred>() => None,
+        res => Some(RpcResponse::new(call, res)),
+    }
+}
+
+struct Method<H> {
+    name: &'static str,
+    handler: Handler<H>,
+    description: Value,
+}
+
+/// The methods of a namespace, each registered along with the types it takes and returns.
+pub struct Methods<H> {
+    namespace: &'static str,
+    methods: Vec<Method<H>>,
+}
+
+impl<H: 'static> Methods<H> {
+    fn new(namespace: &'static str) -> Self {
+        Methods {
+            namespace,
+            methods: Vec::new(),
+        }
+    }
+
+    /// A method taking its params as `A`, usually an array or a tuple of the positional params.
+    pub fn method<A, R>(
+        &mut self,
+        name: &'static str,
+        method: fn(&mut H, A) -> anyhow::Result<R>,
+    ) -> &mut Self
+    where
+        A: DeserializeOwned + RpcSchema + 'static,
+        R: Serialize + RpcSchema + 'static,
+    {
+        let description = describe_method(format!("{}:{name}", self.namespace), method);
+        let handler: Handler<H> = Box::new(move |this, call| {
+            let params = match serde_json::from_str(call.params()) {
+                Ok(v) => v,
+                Err(e) => {
+                    return Some(RpcResponse::error(
+                        call,
+                        RpcError::new(
+                            INVALID_PARAMS,
+                            format!("Invalid JSON for the arguments: {e}"),
+                        ),
+                    ))
+                }
+            };
+
+            respond(call, method(this, params))
+        });
+
+        self.push(name, handler, description)
+    }
+
+    pub fn noargs<R>(
+        &mut self,
+        name: &'static str,
+        method: fn(&mut H) -> anyhow::Result<R>,
+    ) -> &mut Self
+    where
+        R: Serialize + RpcSchema + 'static,
+    {
+        let description = describe_noargs_method(format!("{}:{name}", self.namespace), method);
+        let handler: Handler<H> = Box::new(move |this, call| respond(call, method(this)));
+
+        self.push(name, handler, description)
+    }
+
+    fn push(&mut self, name: &'static str, handler: Handler<H>, description: Value) -> &mut Self {
+        assert!(
+            self.methods.iter().all(|m| m.name != name),
+            "{}:{name} is registered twice",
+            self.namespace
+        );
+
+        self.methods.push(Method {
+            name,
+            handler,
+            description,
+        });
+
+        self
+    }
+}
+
+/// Object safe side of a registered namespace, so they can all live in one list.
+trait Dispatch {
+    fn name(&self) -> &'static str;
+
+    fn call(&mut self, method: &str, call: &RpcCall<'_>, caller: ReplyRoute)
+        -> Option<RpcResponse>;
+}
+
+struct Registered<N: RpcNamespace> {
+    handler: N,
+    methods: Methods<N>,
+}
+
+impl<N: RpcNamespace> Dispatch for Registered<N> {
+    fn name(&self) -> &'static str {
+        N::NAME
+    }
+
+    fn call(
+        &mut self,
+        method: &str,
+        call: &RpcCall<'_>,
+        caller: ReplyRoute,
+    ) -> Option<RpcResponse> {
+        let Some(method) = self.methods.methods.iter().find(|m| m.name == method) else {
+            return Some(RpcResponse::error(
+                call,
+                RpcError::new(METHOD_NOT_FOUND, "Invalid method."),
+            ));
+        };
+
+        self.handler.on_call(caller);
+        (method.handler)(&mut self.handler, call)
+    }
+}
+
+/// Routes `namespace:method` calls to whichever namespace registered itself under that name.
+#[derive(Default)]
+pub struct RpcRegistry {
+    namespaces: Vec<Box<dyn Dispatch>>,
+    index: MethodIndex,
+    // calls from the last request that are waiting on something before they can be answered
+    deferred: Vec<PendingCall>,
+}
+
+impl RpcRegistry {
+    pub fn register<N: RpcNamespace>(&mut self, handler: N) -> &mut Self {
+        assert!(
+            self.namespaces.iter().all(|ns| ns.name() != N::NAME),
+            "namespace {} is registered twice",
+            N::NAME
+        );
+
+        let mut methods = Methods::new(N::NAME);
+        N::register(&mut methods);
+
+        self.index
+            .lock()
+            .extend(methods.methods.iter().map(|m| m.description.clone()));
+        self.namespaces
+            .push(Box::new(Registered { handler, methods }));
+
+        self
+    }
+
+    /// Shared list of method descriptions, including namespaces registered later on.
+    pub fn index(&self) -> MethodIndex {
+        Rc::clone(&self.index)
+    }
+
+    /// Calls a single method. `None` if it answers later, see [`RpcRegistry::take_deferred`].
+    pub fn call(&mut self, call: &RpcCall<'_>, caller: ReplyRoute) -> Option<RpcResponse> {
+        let Some((namespace, method)) = call.method.split_once(':') else {
+            return Some(RpcResponse::error(
+                call,
+                RpcError::new(
+                    METHOD_NOT_FOUND,
+                    "Methods need a namespace, like sys:health.",
+                ),
+            ));
+        };
+
+        match self.namespaces.iter_mut().find(|ns| ns.name() == namespace) {
+            Some(ns) => ns.call(method, call, caller),
+            None => Some(RpcResponse::error(
+                call,
+                RpcError::new(METHOD_NOT_FOUND, "Invalid namespace."),
+            )),
+        }
+    }
+
+    /// Calls that deferred their answer since the last time this was called. Only single calls
+    /// get to, inside a batch they're answered with an error instead.
+    pub fn take_deferred(&mut self) -> Vec<PendingCall> {
+        std::mem::take(&mut self.deferred)
+    }
+
+    /// Handles a raw request, either a single call or a JSON-RPC 2.0 batch, answering in the
+    /// same encoding it came in. Nothing gets written if every call in it was a notification.
+    /// Whatever goes wrong gets answered with an error, the returned one is only for logging.
+    pub fn handle(
+        &mut self,
+        buf: &[u8],
+        caller: ReplyRoute,
+        response: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let encoding = caller.encoding;
+        let res = self.handle_request(buf, caller, response);
+        if let Err(ref e) = res {
+            Fault::HandlerFailed.record();
+
+            // whatever got written might be cut off halfway through
+            response.clear();
+            let error = RpcResponse::invalid(
+                guess_version(buf, encoding),
+                None,
+                RpcError::new(INTERNAL_ERROR, format!("Failed to answer the request: {e}")),
+            );
+
+            if encoding.write(response, &error).is_err() {
+                response.clear();
+            }
+        }
+
+        res
+    }
+
+    fn handle_request(
+        &mut self,
+        buf: &[u8],
+        caller: ReplyRoute,
+        response: &mut Vec<u8>,
+    ) -> anyhow::Result<()> {
+        let encoding = caller.encoding;
+        let json = match encoding.to_json(buf) {
+            Ok(json) => json,
+            Err(e) => return Self::parse_error(buf, encoding, response, e),
+        };
+
+        let raw: &RawValue = match serde_json::from_slice(&json) {
+            Ok(v) => v,
+            Err(e) => return Self::parse_error(buf, encoding, response, e.into()),
+        };
+
+        if !raw.get().starts_with('[') {
+            if let Some(res) = self.handle_single(raw, caller) {
+                encoding.write(response, &res)?;
+            }
+
+            return Ok(());
+        }
+
+        let calls: Vec<&RawValue> = serde_json::from_str(raw.get())?;
+        if calls.is_empty() {
+            Fault::InvalidRequest.record();
+            let res = RpcResponse::invalid(
+                RpcVersion::V2,
+                None,
+                RpcError::new(INVALID_REQUEST, "Empty batch."),
+            );
+            encoding.write(response, &res)?;
+            return Ok(());
+        }
+
+        let caller = ReplyRoute {
+            batch: true,
+            ..caller
+        };
+        let responses: Vec<RpcResponse> = calls
+            .into_iter()
+            .filter_map(|call| self.handle_single(call, caller))
+            .collect();
+
+        if !responses.is_empty() {
+            encoding.write(response, &responses)?;
+        }
+
+        Ok(())
+    }
+
+    fn parse_error(
+        buf: &[u8],
+        encoding: Encoding,
+        response: &mut Vec<u8>,
+        e: anyhow::Error,
+    ) -> anyhow::Result<()> {
+        log::error!("Invalid RPC request: {e}");
+        Fault::InvalidRequest.record();
+        let res = RpcResponse::invalid(
+            guess_version(buf, encoding),
+            None,
+            RpcError::new(PARSE_ERROR, format!("Invalid RPC request: {e}")),
+        );
+
+        encoding.write(response, &res)
+    }
+
+    fn handle_single(&mut self, raw: &RawValue, caller: ReplyRoute) -> Option<RpcResponse> {
+        let call: RpcCall<'_> = match serde_json::from_str(raw.get()) {
+            Ok(call) => call,
+            Err(e) => {
+                Fault::InvalidRequest.record();
+
+                // still try to answer in the format and with the id the caller used
+                let (version, id) = match serde_json::from_str::<RequestProbe>(raw.get()) {
+                    Ok(probe) if probe.jsonrpc.is_none() => (RpcVersion::Legacy, probe.id),
+                    Ok(probe) => (RpcVersion::V2, probe.id),
+                    Err(_) => (RpcVersion::V2, None),
+                };
+
+                return Some(RpcResponse::invalid(
+                    version,
+                    id,
+                    RpcError::new(INVALID_REQUEST, format!("Invalid RPC request: {e}")),
+                ));
+            }
+        };
+
+        if call.jsonrpc.is_some_and(|v| v != "2.0") {
+            Fault::InvalidRequest.record();
+            return Some(RpcResponse::error(
+                &call,
+                RpcError::new(INVALID_REQUEST, "Only JSON-RPC 2.0 is supported."),
+            ));
+        }
+
+        let Some(res) = self.call(&call, caller) else {
+            if call.is_notification() {
+                return None;
+            }
+
+            // the batch gets answered all at once, there's no sending this one on its own later
+            if caller.batch {
+                return Some(RpcResponse::error(
+                    &call,
+                    RpcError::new(INTERNAL_ERROR, "Can't be answered inside a batch."),
+                ));
+            }
+
+            self.deferred.push(PendingCall {
+                version: call.version(),
+                id: call.response_id(),
+            });
+
+            return None;
+        };
+
+        (!call.is_notification()).then_some(res)
+    }
+}
+
+/// The version to answer a request in that couldn't be handled, going by whether it has a
+/// `jsonrpc` key. Legacy clients never send one.
+fn guess_version(buf: &[u8], encoding: Encoding) -> RpcVersion {
+    let probe = encoding
+        .to_json(buf)
+        .ok()
+        .and_then(|json| serde_json::from_slice::<RequestProbe>(&json).ok());
+
+    let v2 = match probe {
+        Some(probe) => probe.jsonrpc.is_some(),
+        None => has_version_key(buf, encoding),
+    };
+
+    if v2 {
+        RpcVersion::V2
+    } else {
+        RpcVersion::Legacy
+    }
+}
+
+/// Looks for the `jsonrpc` key in a request too broken to probe. MessagePack strings carry their
+/// length up front instead of quotes, which a value spelled the same matches too.
+fn has_version_key(buf: &[u8], encoding: Encoding) -> bool {
+    match encoding {
+        Encoding::Json => {
+            let key = b"\"jsonrpc\"";
+            buf.windows(key.len()).enumerate().any(|(idx, w)| {
+                let mut rest = buf[idx + key.len()..].iter();
+                w == key && rest.find(|b| !b.is_ascii_whitespace()) == Some(&b':')
+            })
+        }
+        Encoding::MsgPack => buf.windows(8).any(|w| w == b"\xa7jsonrpc"),
+    }
+}
+
+#[derive(Deserialize)]
+struct RequestProbe {
+    #[serde(default)]
+    jsonrpc: Option<serde::de::IgnoredAny>,
+    #[serde(default)]
+    id: Option<RpcId>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rpc::MessageSource;
+
+    const CALLER: ReplyRoute = ReplyRoute {
+        src: MessageSource::HttpRpc,
+        encoding: Encoding::Json,
+        correlation: 0,
+        batch: false,
+    };
+
+    #[derive(Default)]
+    struct Counter {
+        count: i64,
+    }
+
+    impl RpcNamespace for Counter {
+        const NAME: &'static str = "counter";
+
+        fn register(methods: &mut Methods<Self>) {
+            methods
+                .method("add", |this, [by]: [i64; 1]| {
+                    this.count += by;
+                    Ok(this.count)
+                })
+                .noargs("get", |this| Ok(this.count));
+        }
+    }
+
+    struct Twice;
+
+    impl RpcNamespace for Twice {
+        const NAME: &'static str = "twice";
+
+        fn register(methods: &mut Methods<Self>) {
+            methods.noargs("get", |_| Ok(())).noargs("get", |_| Ok(()));
+        }
+    }
+
+    fn registry() -> RpcRegistry {
+        let mut registry = RpcRegistry::default();
+        registry.register(Counter::default());
+        registry
+    }
+
+    // the response as JSON, null when nothing got written
+    fn handle(registry: &mut RpcRegistry, request: &str) -> Value {
+        let mut response = Vec::new();
+        let _ = registry.handle(request.as_bytes(), CALLER, &mut response);
+        if response.is_empty() {
+            return Value::Null;
+        }
+
+        serde_json::from_slice(&response).unwrap()
+    }
+
+    #[test]
+    fn typed_params() {
+        let mut registry = registry();
+
+        let res = handle(
+            &mut registry,
+            r#"{"jsonrpc":"2.0","id":1,"method":"counter:add","params":[2]}"#,
+        );
+        assert_eq!(res["id"], 1);
+        assert_eq!(res["result"], 2);
+
+        // legacy calls get the legacy shape back
+        let res = handle(
+            &mut registry,
+            r#"{"id":2,"method":"counter:add","params":[3]}"#,
+        );
+        assert_eq!(res["res_id"], 2);
+        assert_eq!(res["result"], 5);
+    }
+
+    #[test]
+    fn wrong_params() {
+        let mut registry = registry();
+
+        for params in [r#"["two"]"#, "[1, 2]", "[]"] {
+            let request =
+                format!(r#"{{"jsonrpc":"2.0","id":1,"method":"counter:add","params":{params}}}"#);
+            let res = handle(&mut registry, &request);
+            assert_eq!(res["error"]["code"], INVALID_PARAMS, "params {params}");
+        }
+
+        // nothing got called
+        let res = handle(
+            &mut registry,
+            r#"{"jsonrpc":"2.0","id":1,"method":"counter:get"}"#,
+        );
+        assert_eq!(res["result"], 0);
+    }
+
+    #[test]
+    fn unknown_method_and_namespace() {
+        let mut registry = registry();
+
+        for method in ["counter:nope", "nope:get", "get"] {
+            let request = format!(r#"{{"jsonrpc":"2.0","id":1,"method":"{method}"}}"#);
+            let res = handle(&mut registry, &request);
+            assert_eq!(res["error"]["code"], METHOD_NOT_FOUND, "method {method}");
+        }
+    }
+
+    #[test]
+    fn notifications() {
+        let mut registry = registry();
+
+        let res = handle(
+            &mut registry,
+            r#"{"jsonrpc":"2.0","method":"counter:add","params":[4]}"#,
+        );
+        assert_eq!(res, Value::Null);
+
+        // a batch of only notifications gets no answer at all
+        let res = handle(
+            &mut registry,
+            r#"[{"jsonrpc":"2.0","method":"counter:add","params":[1]},
+                {"jsonrpc":"2.0","method":"counter:nope"}]"#,
+        );
+        assert_eq!(res, Value::Null);
+
+        // but a null id still does
+        let res = handle(
+            &mut registry,
+            r#"{"jsonrpc":"2.0","id":null,"method":"counter:get"}"#,
+        );
+        assert_eq!(res["id"], Value::Null);
+        assert_eq!(res["result"], 5);
+    }
+
+    #[test]
+    fn parse_errors_keep_the_callers_version() {
+        let mut registry = registry();
+
+        // only the key counts, not the word turning up in the params
+        let res = handle(
+            &mut registry,
+            r#"{"id":1,"method":"counter:add","params":["jsonrpc"]"#,
+        );
+        assert!(res.get("jsonrpc").is_none());
+        assert!(res["error"].is_string());
+
+        let res = handle(&mut registry, r#"{"jsonrpc" : "2.0","id":1,"method":"#);
+        assert_eq!(res["jsonrpc"], "2.0");
+        assert_eq!(res["error"]["code"], PARSE_ERROR);
+    }
+
+    #[test]
+    #[should_panic(expected = "namespace counter is registered twice")]
+    fn namespace_registered_twice() {
+        registry().register(Counter::default());
+    }
+
+    #[test]
+    #[should_panic(expected = "twice:get is registered twice")]
+    fn method_registered_twice() {
+        RpcRegistry::default().register(Twice);
+    }
+}
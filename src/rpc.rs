@@ -1,28 +1,93 @@
-use serde::Serialize;
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
 use serde_json::value::RawValue;
 use thingbuf::mpsc::{
     self,
-    blocking::{Receiver, Sender, StaticChannel, StaticSender},
+    blocking::{Receiver, Sender, StaticSender},
 };
 
+use crate::impl_rpc_schema;
+
 #[repr(usize)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum MessageSource {
     BleRpc,
     BleLovense,
     HttpRpc,
-    Uart, // Timer,
-          // WsRpc,
-          // Invalid
+    // calls over the `/events` websocket, the correlation is the session
+    WsRpc,
+    Uart,
+    // panel lines that didn't come off the UART, from /uart/monitor or sys:fake_uart
+    Injected,
+    Timer,
+    // Invalid
 }
 
+impl_rpc_schema!(MessageSource => [
+    "ble_rpc",
+    "ble_lovense",
+    "http_rpc",
+    "ws_rpc",
+    "uart",
+    "injected",
+    "timer"
+]);
+
 pub struct RequestMessage {
     pub buffer: Vec<u8>,
     pub src: MessageSource,
+    pub encoding: Encoding,
+    // copied onto the response, so a transport with several requests in flight can match them up
+    pub correlation: u32,
+}
+
+/// Where the response to a request has to go, kept for calls that get answered later.
+#[derive(Clone, Copy, Debug)]
+pub struct ReplyRoute {
+    pub src: MessageSource,
+    pub encoding: Encoding,
+    pub correlation: u32,
+    // part of a JSON-RPC 2.0 batch, which gets answered all at once
+    pub batch: bool,
+}
+
+/// How an RPC request and its response are encoded on the wire.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    // MessagePack, for BLE clients where every byte counts
+    MsgPack,
+}
+
+impl Encoding {
+    /// The request as JSON, which is what the handlers parse. Only MessagePack needs converting.
+    pub fn to_json(self, buf: &[u8]) -> anyhow::Result<Cow<'_, [u8]>> {
+        match self {
+            Encoding::Json => Ok(Cow::Borrowed(buf)),
+            Encoding::MsgPack => {
+                let value: serde_json::Value = rmp_serde::from_slice(buf)?;
+                Ok(Cow::Owned(serde_json::to_vec(&value)?))
+            }
+        }
+    }
+
+    pub fn write<T: Serialize>(self, out: &mut Vec<u8>, value: &T) -> anyhow::Result<()> {
+        match self {
+            Encoding::Json => serde_json::to_writer(out, value)?,
+            Encoding::MsgPack => rmp_serde::encode::write_named(out, value)?,
+        }
+
+        Ok(())
+    }
 }
 
 pub struct ResponseMessage {
     pub buffer: Vec<u8>,
     pub tag: ResponseTag,
+    pub correlation: u32,
 }
 
 #[repr(u8)]
@@ -31,6 +96,8 @@ pub enum ResponseTag {
     Normal,
     Lovense,
     BleRpc,
+    // a BLE RPC response encoded with MessagePack
+    BleMsgPack,
     Log,
     Discard,
 }
@@ -53,6 +120,8 @@ impl thingbuf::Recycle<RequestMessage> for MessageRecycler {
         RequestMessage {
             buffer: Vec::with_capacity(self.min_size),
             src: MessageSource::BleRpc,
+            encoding: Encoding::Json,
+            correlation: 0,
         }
     }
 
@@ -60,6 +129,8 @@ impl thingbuf::Recycle<RequestMessage> for MessageRecycler {
         element.buffer.clear();
         element.buffer.shrink_to(self.max_size);
         element.src = MessageSource::BleRpc;
+        element.encoding = Encoding::Json;
+        element.correlation = 0;
     }
 }
 
@@ -68,6 +139,7 @@ impl thingbuf::Recycle<ResponseMessage> for MessageRecycler {
         ResponseMessage {
             buffer: Vec::with_capacity(self.min_size),
             tag: ResponseTag::Normal,
+            correlation: 0,
         }
     }
 
@@ -75,14 +147,10 @@ impl thingbuf::Recycle<ResponseMessage> for MessageRecycler {
         element.buffer.clear();
         element.buffer.shrink_to(self.max_size);
         element.tag = ResponseTag::Normal;
+        element.correlation = 0;
     }
 }
 
-pub static REQUEST_QUEUE: StaticChannel<RequestMessage, 32, MessageRecycler> =
-    StaticChannel::<RequestMessage, 32, MessageRecycler>::with_recycle(MessageRecycler::new(
-        32, 512,
-    ));
-
 pub struct RpcRequester {
     pub req_tx: StaticSender<RequestMessage, MessageRecycler>,
     pub res_rx: Receiver<ResponseMessage, MessageRecycler>,
@@ -108,32 +176,160 @@ pub fn make_channel(
     (RpcRequester { req_tx, res_rx }, res_tx)
 }
 
+// standard JSON-RPC 2.0 error codes, plus SERVER_ERROR for errors coming out of a method
+pub const PARSE_ERROR: i32 = -32700;
+pub const INVALID_REQUEST: i32 = -32600;
+pub const METHOD_NOT_FOUND: i32 = -32601;
+pub const INVALID_PARAMS: i32 = -32602;
+pub const INTERNAL_ERROR: i32 = -32603;
+pub const SERVER_ERROR: i32 = -32000;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(untagged)]
+pub enum RpcId {
+    Number(i64),
+    String(String),
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum RpcVersion {
+    // `{"method", "id", "params"}` in, `{"res_id", "result", "error"}` out
+    Legacy,
+    V2,
+}
+
 #[derive(serde::Deserialize)]
 pub struct RpcCall<'a> {
+    #[serde(default)]
+    pub jsonrpc: Option<&'a str>,
     pub method: &'a str,
-    pub id: u8,
-    pub params: &'a RawValue,
+    // no id on a 2.0 call makes it a notification, `"id": null` still gets an answer
+    #[serde(default, deserialize_with = "present")]
+    pub id: Option<Option<RpcId>>,
+    #[serde(default, borrow)]
+    pub params: Option<&'a RawValue>,
+}
+
+// tells a field that's there but null apart from one that's left out, which `default` covers
+fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl RpcCall<'_> {
+    pub fn version(&self) -> RpcVersion {
+        match self.jsonrpc {
+            Some(_) => RpcVersion::V2,
+            None => RpcVersion::Legacy,
+        }
+    }
+
+    pub fn is_notification(&self) -> bool {
+        self.version() == RpcVersion::V2 && self.id.is_none()
+    }
+
+    /// The id to answer with, null if the caller sent a null one.
+    pub fn response_id(&self) -> Option<RpcId> {
+        self.id.clone().flatten()
+    }
+
+    /// The raw params, an empty array when they're left out.
+    pub fn params(&self) -> &str {
+        self.params.map_or("[]", RawValue::get)
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct RpcError {
+    pub code: i32,
+    pub message: String,
+}
+
+impl RpcError {
+    pub fn new(code: i32, message: impl ToString) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
 }
 
-#[derive(serde::Serialize)]
 pub struct RpcResponse {
-    pub res_id: u8,
-    pub result: serde_json::Value,
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub error: Option<String>,
+    pub id: Option<RpcId>,
+    pub version: RpcVersion,
+    pub outcome: Result<serde_json::Value, RpcError>,
 }
 
 impl RpcResponse {
-    pub fn new<T: Serialize, E: ToString>(id: u8, res: Result<T, E>) -> RpcResponse {
-        let (result, err) = match res {
-            Ok(v) => (serde_json::to_value(v).unwrap(), None),
-            Err(e) => (serde_json::Value::Null, Some(e.to_string())),
+    pub fn new<T: Serialize, E: ToString>(call: &RpcCall<'_>, res: Result<T, E>) -> RpcResponse {
+        Self::answer(call.version(), call.response_id(), res)
+    }
+
+    /// Same as `new`, for when the call itself is long gone and only its id is left.
+    pub fn answer<T: Serialize, E: ToString>(
+        version: RpcVersion,
+        id: Option<RpcId>,
+        res: Result<T, E>,
+    ) -> RpcResponse {
+        let outcome = match res {
+            Ok(v) => serde_json::to_value(v).map_err(|e| RpcError::new(INTERNAL_ERROR, e)),
+            Err(e) => Err(RpcError::new(SERVER_ERROR, e)),
         };
 
         RpcResponse {
-            res_id: id,
-            result,
-            error: err,
+            id,
+            version,
+            outcome,
         }
     }
+
+    /// For requests that couldn't be read far enough to get an `RpcCall` out of them.
+    pub fn invalid(version: RpcVersion, id: Option<RpcId>, error: RpcError) -> RpcResponse {
+        RpcResponse {
+            id,
+            version,
+            outcome: Err(error),
+        }
+    }
+
+    pub fn error(call: &RpcCall<'_>, error: RpcError) -> RpcResponse {
+        RpcResponse {
+            id: call.response_id(),
+            version: call.version(),
+            outcome: Err(error),
+        }
+    }
+}
+
+impl Serialize for RpcResponse {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+
+        let mut map = serializer.serialize_map(None)?;
+        match self.version {
+            RpcVersion::Legacy => {
+                map.serialize_entry("res_id", &self.id)?;
+                match &self.outcome {
+                    Ok(result) => map.serialize_entry("result", result)?,
+                    Err(e) => {
+                        map.serialize_entry("result", &serde_json::Value::Null)?;
+                        map.serialize_entry("error", &e.message)?;
+                    }
+                }
+            }
+            RpcVersion::V2 => {
+                map.serialize_entry("jsonrpc", "2.0")?;
+                map.serialize_entry("id", &self.id)?;
+                match &self.outcome {
+                    Ok(result) => map.serialize_entry("result", result)?,
+                    Err(e) => map.serialize_entry("error", e)?,
+                }
+            }
+        }
+
+        map.end()
+    }
 }
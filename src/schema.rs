@@ -0,0 +1,232 @@
+use std::net::Ipv4Addr;
+
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+
+/// JSON schema of a type that goes in or out of an RPC method, for `sys:describe`.
+pub trait RpcSchema {
+    fn schema() -> Value;
+
+    /// The type laid out as positional params. Arrays and tuples spread out into one param
+    /// per element, everything else is a single param.
+    fn params() -> Vec<Value> {
+        vec![param(0, Self::schema(), true)]
+    }
+}
+
+pub fn param(idx: usize, schema: Value, required: bool) -> Value {
+    json!({
+        "name": format!("arg{idx}"),
+        "required": required,
+        "schema": schema,
+    })
+}
+
+/// Builds a schema out of an example value, which gets listed along with it. Only works for
+/// types without optional fields or enums, an empty `Option` doesn't say what it could hold.
+pub fn from_example<T: Serialize>(title: &str, example: &T) -> Value {
+    let example = serde_json::to_value(example).unwrap_or(Value::Null);
+    let mut schema = shape_of(&example);
+    if let Value::Object(ref mut map) = schema {
+        map.insert("title".into(), title.into());
+        map.insert("examples".into(), json!([example]));
+    }
+
+    schema
+}
+
+/// An object with the given fields, in the order they're listed.
+pub fn object(title: &str, fields: Vec<(&str, Value)>) -> Value {
+    let properties: Map<String, Value> = fields
+        .into_iter()
+        .map(|(name, schema)| (name.to_owned(), schema))
+        .collect();
+
+    json!({ "title": title, "type": "object", "properties": properties })
+}
+
+/// An enum serialized with `#[serde(tag = "..")]`, one object per variant telling them apart
+/// by the tag field.
+pub fn tagged(title: &str, tag: &str, variants: Vec<(&str, Vec<(&str, Value)>)>) -> Value {
+    let variants: Vec<Value> = variants
+        .into_iter()
+        .map(|(variant, fields)| {
+            let mut properties = Map::new();
+            properties.insert(tag.to_owned(), json!({ "const": variant }));
+            properties.extend(
+                fields
+                    .into_iter()
+                    .map(|(name, schema)| (name.to_owned(), schema)),
+            );
+
+            json!({ "type": "object", "properties": properties, "required": [tag] })
+        })
+        .collect();
+
+    json!({ "title": title, "oneOf": variants })
+}
+
+fn shape_of(value: &Value) -> Value {
+    match value {
+        // could be anything, usually an `Option` that happens to be empty
+        Value::Null => json!({}),
+        Value::Bool(_) => json!({ "type": "boolean" }),
+        Value::Number(n) if n.is_f64() => json!({ "type": "number" }),
+        Value::Number(_) => json!({ "type": "integer" }),
+        Value::String(_) => json!({ "type": "string" }),
+        Value::Array(items) => match items.first() {
+            Some(first) => json!({ "type": "array", "items": shape_of(first) }),
+            None => json!({ "type": "array" }),
+        },
+        Value::Object(fields) => {
+            let properties: Map<String, Value> = fields
+                .iter()
+                .map(|(k, v)| (k.clone(), shape_of(v)))
+                .collect();
+
+            json!({ "type": "object", "properties": properties })
+        }
+    }
+}
+
+/// Implements [`RpcSchema`] for types from the rest of the firmware.
+/// `Type => example` describes the type by that example value, `Type => [..]` lists the
+/// strings a unit enum serializes to, `Type => { field: Type, .. }` goes field by field and
+/// `Type => "tag" { "variant" { field: Type, .. }, .. }` covers internally tagged enums.
+/// A bare `Type` only gives its name.
+#[macro_export]
+macro_rules! impl_rpc_schema {
+    ($ty:ty => [$($variant:literal),*]) => {
+        impl $crate::schema::RpcSchema for $ty {
+            fn schema() -> serde_json::Value {
+                serde_json::json!({ "title": stringify!($ty), "type": "string", "enum": [$($variant),*] })
+            }
+        }
+    };
+    ($ty:ty => $tag:literal { $($variant:literal $({ $($field:ident: $fty:ty),* $(,)? })?),* $(,)? }) => {
+        impl $crate::schema::RpcSchema for $ty {
+            fn schema() -> serde_json::Value {
+                $crate::schema::tagged(stringify!($ty), $tag, vec![$(
+                    ($variant, vec![$($(
+                        (stringify!($field), <$fty as $crate::schema::RpcSchema>::schema())
+                    ),*)?]),
+                )*])
+            }
+        }
+    };
+    ($ty:ty => { $($field:ident: $fty:ty),* $(,)? }) => {
+        impl $crate::schema::RpcSchema for $ty {
+            fn schema() -> serde_json::Value {
+                $crate::schema::object(stringify!($ty), vec![$(
+                    (stringify!($field), <$fty as $crate::schema::RpcSchema>::schema()),
+                )*])
+            }
+        }
+    };
+    ($ty:ty => $example:expr) => {
+        impl $crate::schema::RpcSchema for $ty {
+            fn schema() -> serde_json::Value {
+                $crate::schema::from_example(stringify!($ty), &$example)
+            }
+        }
+    };
+    ($ty:ty) => {
+        impl $crate::schema::RpcSchema for $ty {
+            fn schema() -> serde_json::Value {
+                serde_json::json!({ "title": stringify!($ty), "type": "object" })
+            }
+        }
+    };
+}
+
+macro_rules! impl_primitive_schema {
+    ($kind:literal => $($ty:ty),*) => {
+        $(
+            impl RpcSchema for $ty {
+                fn schema() -> Value {
+                    json!({ "type": $kind })
+                }
+            }
+        )*
+    };
+}
+
+impl_primitive_schema!("integer" => u8, u16, u32, u64, usize, i8, i16, i32, i64);
+impl_primitive_schema!("number" => f32, f64);
+impl_primitive_schema!("boolean" => bool);
+impl_primitive_schema!("string" => String);
+impl_primitive_schema!("null" => ());
+
+impl RpcSchema for Value {
+    fn schema() -> Value {
+        json!({})
+    }
+}
+
+impl RpcSchema for Ipv4Addr {
+    fn schema() -> Value {
+        json!({ "type": "string", "format": "ipv4" })
+    }
+}
+
+impl<const N: usize> RpcSchema for heapless::String<N> {
+    fn schema() -> Value {
+        json!({ "type": "string", "maxLength": N })
+    }
+}
+
+impl<T: RpcSchema> RpcSchema for Vec<T> {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema() })
+    }
+}
+
+impl<T: RpcSchema> RpcSchema for Option<T> {
+    fn schema() -> Value {
+        json!({ "anyOf": [T::schema(), { "type": "null" }] })
+    }
+}
+
+impl<T: RpcSchema, const N: usize> RpcSchema for [T; N] {
+    fn schema() -> Value {
+        json!({ "type": "array", "items": T::schema(), "minItems": N, "maxItems": N })
+    }
+
+    fn params() -> Vec<Value> {
+        (0..N).map(|idx| param(idx, T::schema(), true)).collect()
+    }
+}
+
+impl<A: RpcSchema, B: RpcSchema> RpcSchema for (A, B) {
+    fn schema() -> Value {
+        json!({ "type": "array", "prefixItems": [A::schema(), B::schema()] })
+    }
+
+    fn params() -> Vec<Value> {
+        vec![param(0, A::schema(), true), param(1, B::schema(), true)]
+    }
+}
+
+/// Describes a method taking params, with the types picked up from the method itself.
+pub fn describe_method<H, A: RpcSchema, R: RpcSchema>(
+    name: String,
+    _method: fn(&mut H, A) -> anyhow::Result<R>,
+) -> Value {
+    describe(name, A::params(), R::schema())
+}
+
+pub fn describe_noargs_method<H, R: RpcSchema>(
+    name: String,
+    _method: fn(&mut H) -> anyhow::Result<R>,
+) -> Value {
+    describe(name, Vec::new(), R::schema())
+}
+
+fn describe(name: String, params: Vec<Value>, result: Value) -> Value {
+    json!({
+        "name": name,
+        "paramStructure": "by-position",
+        "params": params,
+        "result": { "name": "result", "schema": result },
+    })
+}
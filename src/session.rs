@@ -0,0 +1,128 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigType, hal::wand::Wand, impl_conf_type, impl_rpc_schema};
+
+#[derive(Serialize, Deserialize)]
+pub struct SessionConfig {
+    // seconds without any incoming command before the motor is turned off. 0 disables it
+    pub idle_timeout: u32,
+    // seconds the motor may run continuously before it is turned off. 0 disables it
+    pub max_session: u32,
+}
+
+impl_rpc_schema!(SessionConfig => SessionConfig::default());
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            idle_timeout: 600,
+            max_session: 3600,
+        }
+    }
+}
+
+impl_conf_type!(SessionConfig, "/littlefs/session.json", SESSION_CONFIG);
+
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StopReason {
+    IdleTimeout,
+    MaxSession,
+}
+
+impl_rpc_schema!(StopReason => ["idle_timeout", "max_session"]);
+
+#[derive(Serialize)]
+pub struct SessionReport {
+    idle_timeout: u32,
+    max_session: u32,
+    idle_for: u64,
+    running_for: Option<u64>,
+    last_stop: Option<StopReason>,
+}
+
+impl_rpc_schema!(SessionReport => {
+    idle_timeout: u32,
+    max_session: u32,
+    idle_for: u64,
+    running_for: Option<u64>,
+    last_stop: Option<StopReason>,
+});
+
+pub struct SessionGuard {
+    last_activity: Instant,
+    running_since: Option<Instant>,
+    last_stop: Option<StopReason>,
+}
+
+impl Default for SessionGuard {
+    fn default() -> Self {
+        SessionGuard {
+            last_activity: Instant::now(),
+            running_since: None,
+            last_stop: None,
+        }
+    }
+}
+
+impl SessionGuard {
+    pub fn activity(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Turns the wand off once either limit is hit. Patterns are deliberate, so they don't idle out.
+    pub fn tick(
+        &mut self,
+        now: Instant,
+        wand: &mut Wand,
+        pattern_playing: bool,
+    ) -> Option<StopReason> {
+        if wand.get_percent() == 0 && wand.get_actual() == 0 {
+            self.running_since = None;
+            return None;
+        }
+
+        let running_since = *self.running_since.get_or_insert(now);
+        let config = SessionConfig::read();
+
+        let idle_timeout = Duration::from_secs(config.idle_timeout as u64);
+        let max_session = Duration::from_secs(config.max_session as u64);
+
+        let reason = if config.idle_timeout > 0
+            && !pattern_playing
+            && now.duration_since(self.last_activity) >= idle_timeout
+        {
+            StopReason::IdleTimeout
+        } else if config.max_session > 0 && now.duration_since(running_since) >= max_session {
+            StopReason::MaxSession
+        } else {
+            return None;
+        };
+
+        log::warn!(target: "session", "Turning the motor off: {reason:?}");
+        wand.set_percent(0);
+        self.running_since = None;
+        self.last_stop = Some(reason);
+
+        Some(reason)
+    }
+
+    pub fn running(&self) -> bool {
+        self.running_since.is_some()
+    }
+
+    pub fn report(&self, now: Instant) -> SessionReport {
+        let config = SessionConfig::read();
+        SessionReport {
+            idle_timeout: config.idle_timeout,
+            max_session: config.max_session,
+            idle_for: now.duration_since(self.last_activity).as_secs(),
+            running_for: self
+                .running_since
+                .map(|since| now.duration_since(since).as_secs()),
+            last_stop: self.last_stop,
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{config::ConfigType, impl_conf_type, impl_rpc_schema};
+
+// keeps flash wear bounded: at most one write every few minutes while the motor is in use
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+pub struct UsageStats {
+    pub motor_on_ms: u64,
+    // time spent at 1-25%, 26-50%, 51-75% and 76-100%
+    pub intensity_ms: [u64; 4],
+    pub sessions: u32,
+    pub peak_temperature: f32,
+    pub boot_count: u32,
+}
+
+impl_rpc_schema!(UsageStats => UsageStats::default());
+
+impl_conf_type!(UsageStats, "/littlefs/stats.json", USAGE_STATS);
+
+pub struct StatsTracker {
+    stats: UsageStats,
+    in_session: bool,
+    dirty: bool,
+    last_tick: Instant,
+    last_flush: Instant,
+}
+
+impl StatsTracker {
+    /// Loads the stored stats and counts this boot.
+    pub fn load() -> Self {
+        let mut stats = (**UsageStats::read()).clone();
+        stats.boot_count += 1;
+        if let Err(e) = stats.clone().store() {
+            log::error!("Failed to persist usage stats: {e}");
+        }
+
+        let now = Instant::now();
+        StatsTracker {
+            stats,
+            in_session: false,
+            dirty: false,
+            last_tick: now,
+            last_flush: now,
+        }
+    }
+
+    pub fn tick(&mut self, now: Instant, actual_percent: i64, temperature: f32) {
+        let elapsed = now.duration_since(self.last_tick).as_millis() as u64;
+        self.last_tick = now;
+
+        if actual_percent > 0 {
+            let bucket = ((actual_percent - 1) / 25).clamp(0, 3) as usize;
+            self.stats.motor_on_ms += elapsed;
+            self.stats.intensity_ms[bucket] += elapsed;
+            self.dirty = true;
+        }
+
+        if temperature > self.stats.peak_temperature {
+            self.stats.peak_temperature = temperature;
+            self.dirty = true;
+        }
+
+        if self.dirty && now.duration_since(self.last_flush) >= FLUSH_INTERVAL {
+            // a failed write waits for the next interval too, instead of retrying every tick
+            self.last_flush = now;
+            if let Err(e) = self.flush() {
+                log::error!("Failed to persist usage stats: {e}");
+            }
+        }
+    }
+
+    /// Counts a session whenever the [`SessionGuard`](crate::session::SessionGuard) starts one,
+    /// so pattern pulses and kick-start dips don't count as several.
+    pub fn session(&mut self, running: bool) {
+        if running && !self.in_session {
+            self.stats.sessions += 1;
+            self.dirty = true;
+        }
+        self.in_session = running;
+    }
+
+    pub fn flush(&mut self) -> anyhow::Result<()> {
+        self.stats.clone().store()?;
+        self.dirty = false;
+        self.last_flush = Instant::now();
+
+        Ok(())
+    }
+
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        self.stats = UsageStats::default();
+        self.flush()
+    }
+
+    pub fn stats(&self) -> UsageStats {
+        self.stats.clone()
+    }
+}
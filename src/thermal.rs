@@ -0,0 +1,183 @@
+use std::time::{Duration, Instant};
+
+use anyhow::bail;
+use esp_idf_svc::hal::temp_sensor::TempSensorDriver;
+use serde::{Deserialize, Serialize};
+use thingbuf::mpsc::blocking::StaticSender;
+
+use crate::{
+    config::ConfigType,
+    hal::{
+        lights::{Animation, LightCommand},
+        wand::Wand,
+    },
+    impl_conf_type, impl_rpc_schema,
+};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+#[derive(Serialize, Deserialize)]
+pub struct ThermalConfig {
+    // degrees celsius at which the maximum duty starts being scaled down
+    pub soft_limit: f32,
+    // degrees celsius at which the motor is shut off until reset
+    pub hard_limit: f32,
+    // maximum percent allowed right before the hard limit is reached
+    pub min_percent: i64,
+}
+
+impl_rpc_schema!(ThermalConfig => ThermalConfig::default());
+
+impl Default for ThermalConfig {
+    fn default() -> Self {
+        ThermalConfig {
+            soft_limit: 60.0,
+            hard_limit: 75.0,
+            min_percent: 30,
+        }
+    }
+}
+
+impl_conf_type!(ThermalConfig, "/littlefs/thermal.json", THERMAL_CONFIG);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ThermalState {
+    Normal,
+    Derating,
+    Shutdown,
+}
+
+impl_rpc_schema!(ThermalState => ["normal", "derating", "shutdown"]);
+
+#[derive(Serialize)]
+pub struct ThermalReport {
+    temperature: f32,
+    state: ThermalState,
+    max_percent: i64,
+    soft_limit: f32,
+    hard_limit: f32,
+}
+
+impl_rpc_schema!(ThermalReport => {
+    temperature: f32,
+    state: ThermalState,
+    max_percent: i64,
+    soft_limit: f32,
+    hard_limit: f32,
+});
+
+pub struct ThermalSupervisor {
+    sensor: TempSensorDriver<'static>,
+    uart_tx: StaticSender<LightCommand>,
+    temperature: f32,
+    state: ThermalState,
+    max_percent: i64,
+    last_poll: Instant,
+}
+
+impl ThermalSupervisor {
+    pub fn new(sensor: TempSensorDriver<'static>, uart_tx: StaticSender<LightCommand>) -> Self {
+        ThermalSupervisor {
+            sensor,
+            uart_tx,
+            temperature: f32::NAN,
+            state: ThermalState::Normal,
+            max_percent: 100,
+            last_poll: Instant::now(),
+        }
+    }
+
+    pub fn read_sensor(&mut self) -> anyhow::Result<f32> {
+        self.temperature = self.sensor.get_celsius()?;
+        Ok(self.temperature)
+    }
+
+    /// Last temperature read from the sensor.
+    pub fn temperature(&self) -> f32 {
+        self.temperature
+    }
+
+    pub fn state(&self) -> ThermalState {
+        self.state
+    }
+
+    pub fn tick(&mut self, now: Instant, wand: &mut Wand) {
+        if now.duration_since(self.last_poll) < POLL_INTERVAL {
+            return;
+        }
+        self.last_poll = now;
+
+        if self.state == ThermalState::Shutdown {
+            // latched until someone resets us
+            return;
+        }
+
+        let temperature = match self.read_sensor() {
+            Ok(t) => t,
+            Err(e) => {
+                log::error!(target: "thermal", "Failed to read temperature: {e}");
+                return;
+            }
+        };
+
+        let config = ThermalConfig::read();
+
+        if temperature >= config.hard_limit {
+            log::error!(target: "thermal", "{temperature:.1}°C is over the hard limit, shutting down the motor");
+            self.state = ThermalState::Shutdown;
+            self.max_percent = 0;
+            wand.set_fault(true);
+            let _ = self.uart_tx.send(LightCommand::Play(Animation::Warning));
+            return;
+        }
+
+        let (state, max_percent) = if temperature >= config.soft_limit {
+            let over = (temperature - config.soft_limit) / (config.hard_limit - config.soft_limit);
+            let derated = 100.0 - over * (100 - config.min_percent) as f32;
+            (ThermalState::Derating, derated as i64)
+        } else {
+            (ThermalState::Normal, 100)
+        };
+
+        if state != self.state {
+            log::info!(target: "thermal", "{temperature:.1}°C: {:?} -> {state:?}", self.state);
+        }
+
+        self.state = state;
+        self.max_percent = max_percent;
+        wand.set_limit(max_percent);
+    }
+
+    /// Clears a latched shutdown, as long as the temperature is back under the soft limit.
+    pub fn reset(&mut self, wand: &mut Wand) -> anyhow::Result<()> {
+        if self.state != ThermalState::Shutdown {
+            return Ok(());
+        }
+
+        let temperature = self.read_sensor()?;
+        let soft_limit = ThermalConfig::read().soft_limit;
+        if temperature >= soft_limit {
+            bail!("Still too hot to reset: {temperature:.1}°C (needs to be under {soft_limit:.1}°C).");
+        }
+
+        self.state = ThermalState::Normal;
+        self.max_percent = 100;
+        wand.set_limit(100);
+        wand.set_fault(false);
+        let _ = self.uart_tx.send(LightCommand::Stop(Animation::Warning));
+
+        Ok(())
+    }
+
+    pub fn report(&self) -> ThermalReport {
+        let config = ThermalConfig::read();
+        ThermalReport {
+            temperature: self.temperature,
+            state: self.state,
+            max_percent: self.max_percent,
+            soft_limit: config.soft_limit,
+            hard_limit: config.hard_limit,
+        }
+    }
+}
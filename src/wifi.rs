@@ -12,7 +12,7 @@ use log::info;
 use parking_lot::lock_api::Mutex;
 use serde::{Deserialize, Serialize};
 
-use crate::{config::ConfigType, impl_conf_type};
+use crate::{config::ConfigType, impl_conf_type, impl_rpc_schema};
 
 #[derive(Serialize, Deserialize, Default)]
 pub struct WifiConfig {
@@ -20,6 +20,8 @@ pub struct WifiConfig {
     pub authentication: WifiAuthentication,
 }
 
+impl_rpc_schema!(WifiConfig => { ssid: heapless::String<32>, authentication: WifiAuthentication });
+
 #[derive(Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[derive(Default)]
@@ -36,6 +38,12 @@ pub enum WifiAuthentication {
     None,
 }
 
+impl_rpc_schema!(WifiAuthentication => "type" {
+    "WPA2Personal" { password: heapless::String<64> },
+    "WPA2Enterprise" { identity: String, username: String, password: String },
+    "None",
+});
+
 impl_conf_type!(WifiConfig, "/littlefs/wifi.json", WIFI_CONFIG);
 
 #[derive(Clone)]